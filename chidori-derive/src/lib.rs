@@ -0,0 +1,591 @@
+//! Derive macros for chidori nodes.
+//!
+//! - `#[derive(MaelstromPayload)]` makes an enum a Maelstrom payload: serialized with its
+//!   variant in snake case as `type`, and with every request paired with its `_ok` response,
+//!   which is the only one it can be answered with.
+//! - `#[handler]` fills in the `Handler` methods an implementation leaves out.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse_macro_input;
+use syn::spanned::Spanned;
+
+/// Implements `Serialize`, `Deserialize` and `chidori::MaelstromPayload` for an enum of
+/// payloads, in place of `#[derive(Serialize, Deserialize)]` with `#[serde(tag = "type")]` and
+/// `#[serde(rename_all = "snake_case")]`. Variants need named fields, if any. `#[serde(...)]`
+/// attributes on variants and fields are kept, those on the enum itself are rejected since its
+/// representation is fixed.
+///
+/// A variant whose type is `x_ok` is the response to the variant whose type is `x`; one with
+/// no such request is rejected. For every such request variant `X`, the enum gets a
+/// `respond_x(channel, received, fields..)` function, which replies to `received` with the
+/// response built from its fields in order, so a request cannot be answered with the response
+/// to another. It fails if `received` is not an `x`.
+///
+/// ```
+/// use chidori::MaelstromPayload;
+///
+/// #[derive(MaelstromPayload)]
+/// enum Payload {
+///     Echo {
+///         #[serde(skip_serializing_if = "String::is_empty", default)]
+///         echo: String,
+///     },
+///     EchoOk {
+///         #[serde(with = "hex")]
+///         echo: u64,
+///     },
+/// }
+///
+/// mod hex {
+///     pub fn serialize<S: serde::Serializer>(v: &u64, s: S) -> Result<S::Ok, S::Error> {
+///         s.serialize_str(&format!("{v:x}"))
+///     }
+///
+///     pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+///         let s: String = serde::Deserialize::deserialize(d)?;
+///         u64::from_str_radix(&s, 16).map_err(serde::de::Error::custom)
+///     }
+/// }
+///
+/// let echo = serde_json::to_string(&Payload::Echo { echo: String::new() }).unwrap();
+/// assert_eq!(echo, r#"{"type":"echo"}"#);
+/// let echo_ok = serde_json::to_string(&Payload::EchoOk { echo: 255 }).unwrap();
+/// assert_eq!(echo_ok, r#"{"type":"echo_ok","echo":"ff"}"#);
+/// let Payload::EchoOk { echo } = serde_json::from_str(&echo_ok).unwrap() else {
+///     unreachable!()
+/// };
+/// assert_eq!(echo, 255);
+/// ```
+///
+/// Responders only take messages carrying the payload, so that they can check it is their
+/// request. A payload inside an envelope is taken out with `Message::with_payload`:
+///
+/// ```compile_fail
+/// # use chidori::MaelstromPayload;
+/// #[derive(MaelstromPayload)]
+/// enum Payload {
+///     Echo { echo: String },
+///     EchoOk { echo: String },
+/// }
+///
+/// fn reply(
+///     channel: &mut chidori::channel::MessageChannel,
+///     received: &chidori::message::Message<serde_json::Value>,
+/// ) -> Result<(), &'static str> {
+///     Payload::respond_echo(channel, received, String::new())
+/// }
+/// ```
+///
+/// Responses need a request:
+///
+/// ```compile_fail
+/// # use chidori::MaelstromPayload;
+/// #[derive(MaelstromPayload)]
+/// enum Payload {
+///     ReadOk { value: i64 },
+/// }
+/// ```
+///
+/// And the representation cannot be changed:
+///
+/// ```compile_fail
+/// # use chidori::MaelstromPayload;
+/// #[derive(MaelstromPayload)]
+/// #[serde(tag = "kind")]
+/// enum Payload {
+///     Read,
+/// }
+/// ```
+#[proc_macro_derive(MaelstromPayload, attributes(serde))]
+pub fn derive_maelstrom_payload(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    expand_payload(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct Variant {
+    ident: syn::Ident,
+    message_type: String,
+    attrs: Vec<syn::Attribute>,
+    /// Named fields with their serde attributes, or `None` for a unit variant.
+    fields: Option<Vec<Field>>,
+}
+
+struct Field {
+    attrs: Vec<syn::Attribute>,
+    ident: syn::Ident,
+    ty: syn::Type,
+}
+
+fn serde_attrs(attrs: &[syn::Attribute]) -> Vec<syn::Attribute> {
+    attrs
+        .iter()
+        .filter(|a| a.path().is_ident("serde"))
+        .cloned()
+        .collect()
+}
+
+fn snake_case(ident: &syn::Ident) -> String {
+    let mut snake = String::new();
+    for (i, c) in ident.to_string().chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+/// The type serde gives the variant: its `rename`, or its name in snake case.
+fn message_type(variant: &syn::Variant) -> syn::Result<String> {
+    let mut renamed = None;
+    for attr in variant.attrs.iter().filter(|a| a.path().is_ident("serde")) {
+        // anything else than a rename is left for serde to check
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                if !meta.input.peek(syn::Token![=]) {
+                    return Err(meta.error(
+                        "only `rename = \"..\"` is supported, the type must be the same both ways",
+                    ));
+                }
+                let value: syn::LitStr = meta.value()?.parse()?;
+                renamed = Some(value.value());
+            } else if meta.input.peek(syn::Token![=]) {
+                meta.value()?.parse::<syn::Expr>()?;
+            } else if meta.input.peek(syn::token::Paren) {
+                meta.parse_nested_meta(|nested| {
+                    if nested.input.peek(syn::Token![=]) {
+                        nested.value()?.parse::<syn::Expr>()?;
+                    }
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(renamed.unwrap_or_else(|| snake_case(&variant.ident)))
+}
+
+/// The serde attributes of a field for the enum serialized from references to its fields.
+/// Functions given to serde get a `&&T` there, so they are called through shims, returned along
+/// with the attributes, that take that and pass on the `&T`.
+fn borrowed_attrs(
+    variant: &syn::Ident,
+    field: &Field,
+) -> syn::Result<(Vec<syn::Attribute>, Vec<TokenStream2>)> {
+    let mut attrs = Vec::new();
+    let mut shims = Vec::new();
+    for attr in &field.attrs {
+        let metas = attr.parse_args_with(
+            syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated,
+        )?;
+        let mut kept = Vec::new();
+        for meta in metas {
+            let syn::Meta::NameValue(name_value) = &meta else {
+                kept.push(meta);
+                continue;
+            };
+            let Some(key) = ["with", "serialize_with", "skip_serializing_if"]
+                .into_iter()
+                .find(|key| name_value.path.is_ident(key))
+            else {
+                kept.push(meta);
+                continue;
+            };
+            let syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(function),
+                ..
+            }) = &name_value.value
+            else {
+                return Err(syn::Error::new_spanned(
+                    &name_value.value,
+                    "expected a string",
+                ));
+            };
+            let mut function: syn::ExprPath = function.parse()?;
+            if key == "with" {
+                function.path.segments.push(syn::parse_quote!(serialize));
+            }
+
+            let ty = &field.ty;
+            let shim = quote::format_ident!(
+                "__{}_{}_{}",
+                key,
+                snake_case(variant),
+                field.ident.to_string().trim_start_matches("r#")
+            );
+            let shim_name = shim.to_string();
+            if key == "skip_serializing_if" {
+                shims.push(quote! {
+                    fn #shim(value: &&#ty) -> bool {
+                        #function(*value)
+                    }
+                });
+                kept.push(syn::parse_quote!(skip_serializing_if = #shim_name));
+            } else {
+                shims.push(quote! {
+                    fn #shim<__S>(
+                        value: &&#ty,
+                        serializer: __S,
+                    ) -> ::core::result::Result<__S::Ok, __S::Error>
+                    where
+                        __S: ::serde::Serializer,
+                    {
+                        #function(*value, serializer)
+                    }
+                });
+                kept.push(syn::parse_quote!(serialize_with = #shim_name));
+            }
+        }
+        attrs.push(syn::parse_quote!(#[serde(#(#kept),*)]));
+    }
+    Ok((attrs, shims))
+}
+
+fn expand_payload(input: syn::DeriveInput) -> syn::Result<TokenStream2> {
+    if let Some(attr) = input.attrs.iter().find(|a| a.path().is_ident("serde")) {
+        return Err(syn::Error::new_spanned(
+            attr,
+            "MaelstromPayload fixes the representation, serde attributes go on variants and fields",
+        ));
+    }
+    let syn::Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "MaelstromPayload can only be derived for enums",
+        ));
+    };
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "MaelstromPayload cannot be derived for generic enums",
+        ));
+    }
+    if data.variants.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "MaelstromPayload needs at least one variant",
+        ));
+    }
+
+    let mut variants = Vec::new();
+    for variant in &data.variants {
+        let fields = match &variant.fields {
+            syn::Fields::Unit => None,
+            syn::Fields::Named(named) => Some(
+                named
+                    .named
+                    .iter()
+                    .map(|f| Field {
+                        attrs: serde_attrs(&f.attrs),
+                        ident: f.ident.clone().unwrap(),
+                        ty: f.ty.clone(),
+                    })
+                    .collect(),
+            ),
+            syn::Fields::Unnamed(_) => {
+                return Err(syn::Error::new(
+                    variant.span(),
+                    "payload variants need named fields",
+                ))
+            }
+        };
+        variants.push(Variant {
+            ident: variant.ident.clone(),
+            message_type: message_type(variant)?,
+            attrs: serde_attrs(&variant.attrs),
+            fields,
+        });
+    }
+
+    for variant in &variants {
+        let Some(request) = variant.message_type.strip_suffix("_ok") else {
+            continue;
+        };
+        if !variants.iter().any(|v| v.message_type == request) {
+            return Err(syn::Error::new_spanned(
+                &variant.ident,
+                format!("{} has no request {request}", variant.message_type),
+            ));
+        }
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let mut responders = Vec::new();
+    for request in &variants {
+        let response_type = format!("{}_ok", request.message_type);
+        let Some(response) = variants.iter().find(|v| v.message_type == response_type) else {
+            continue;
+        };
+        let responder = quote::format_ident!("respond_{}", snake_case(&request.ident));
+        let doc = format!(
+            "Replies to a `{}` request with its `{}` response.",
+            request.message_type, response.message_type
+        );
+        let request_ident = &request.ident;
+        let response_ident = &response.ident;
+        let (params, construct) = match &response.fields {
+            None => (quote!(), quote!(#name::#response_ident)),
+            Some(fields) => {
+                let field_names: Vec<_> = fields.iter().map(|f| &f.ident).collect();
+                let field_types: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+                (
+                    quote!(#(, #field_names: #field_types)*),
+                    quote!(#name::#response_ident { #(#field_names),* }),
+                )
+            }
+        };
+        let mismatch = format!("not a {} request", request.message_type);
+        responders.push(quote! {
+            #[doc = #doc]
+            #vis fn #responder(
+                channel: &mut ::chidori::channel::MessageChannel,
+                received: &::chidori::message::Message<#name>
+                #params
+            ) -> ::core::result::Result<(), &'static str> {
+                match received.body.payload {
+                    #name::#request_ident { .. } => channel.reply(received, &#construct),
+                    _ => Err(#mismatch),
+                }
+            }
+        });
+    }
+
+    let idents: Vec<_> = variants.iter().map(|v| &v.ident).collect();
+    let message_types: Vec<_> = variants.iter().map(|v| &v.message_type).collect();
+    let response_types: Vec<_> = variants
+        .iter()
+        .map(|v| {
+            let response = format!("{}_ok", v.message_type);
+            if variants.iter().any(|other| other.message_type == response) {
+                quote!(Some(#response))
+            } else {
+                quote!(None)
+            }
+        })
+        .collect();
+
+    let mut borrowed = Vec::new();
+    let mut shims = Vec::new();
+    let mut owned = Vec::new();
+    let mut to_borrowed = Vec::new();
+    let mut from_owned = Vec::new();
+    for variant in &variants {
+        let ident = &variant.ident;
+        let attrs = &variant.attrs;
+        match &variant.fields {
+            None => {
+                borrowed.push(quote!(#(#attrs)* #ident));
+                owned.push(quote!(#(#attrs)* #ident));
+                to_borrowed.push(quote!(#name::#ident => __Borrowed::#ident));
+                from_owned.push(quote!(__Owned::#ident => #name::#ident));
+            }
+            Some(fields) => {
+                let field_attrs: Vec<_> = fields.iter().map(|f| &f.attrs).collect();
+                let field_names: Vec<_> = fields.iter().map(|f| &f.ident).collect();
+                let field_types: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+                let mut borrowed_fields = Vec::new();
+                for field in fields {
+                    let (field_attrs, field_shims) = borrowed_attrs(ident, field)?;
+                    let (field_name, field_type) = (&field.ident, &field.ty);
+                    borrowed_fields
+                        .push(quote!(#(#field_attrs)* #field_name: &'__payload #field_type));
+                    shims.extend(field_shims);
+                }
+                borrowed.push(quote! {
+                    #(#attrs)* #ident { #(#borrowed_fields),* }
+                });
+                owned.push(quote! {
+                    #(#attrs)* #ident { #(#(#field_attrs)* #field_names: #field_types),* }
+                });
+                to_borrowed.push(quote! {
+                    #name::#ident { #(#field_names),* } => __Borrowed::#ident { #(#field_names),* }
+                });
+                from_owned.push(quote! {
+                    __Owned::#ident { #(#field_names),* } => #name::#ident { #(#field_names),* }
+                });
+            }
+        }
+    }
+
+    Ok(quote! {
+        const _: () = {
+            #(#shims)*
+
+            #[derive(::serde::Serialize)]
+            #[serde(rename_all = "snake_case")]
+            #[serde(tag = "type")]
+            enum __Borrowed<'__payload> {
+                #(#borrowed,)*
+                #[serde(skip)]
+                #[allow(dead_code)]
+                __Lifetime(::core::marker::PhantomData<&'__payload ()>),
+            }
+
+            #[derive(::serde::Deserialize)]
+            #[serde(rename_all = "snake_case")]
+            #[serde(tag = "type")]
+            enum __Owned {
+                #(#owned,)*
+            }
+
+            impl ::serde::Serialize for #name {
+                fn serialize<S>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error>
+                where
+                    S: ::serde::Serializer,
+                {
+                    let borrowed = match self {
+                        #(#to_borrowed,)*
+                    };
+                    ::serde::Serialize::serialize(&borrowed, serializer)
+                }
+            }
+
+            impl<'de> ::serde::Deserialize<'de> for #name {
+                fn deserialize<D>(deserializer: D) -> ::core::result::Result<Self, D::Error>
+                where
+                    D: ::serde::Deserializer<'de>,
+                {
+                    let owned = <__Owned as ::serde::Deserialize>::deserialize(deserializer)?;
+                    Ok(match owned {
+                        #(#from_owned,)*
+                    })
+                }
+            }
+
+            #[allow(dead_code)]
+            impl #name {
+                #(#responders)*
+            }
+
+            impl ::chidori::MaelstromPayload for #name {
+                fn message_type(&self) -> &'static str {
+                    match self {
+                        #(#name::#idents { .. } => #message_types,)*
+                    }
+                }
+
+                fn response_type(&self) -> ::core::option::Option<&'static str> {
+                    match self {
+                        #(#name::#idents { .. } => #response_types,)*
+                    }
+                }
+            }
+        };
+    })
+}
+
+/// Completes an `impl chidori::Handler<_>` block: a missing `handle_tick` does nothing, and a
+/// missing `send_events` sends no events, or a tick every `tick_millis` when given as in
+/// `#[handler(tick_millis = 100)]`.
+///
+/// Ticks need somewhere to go:
+///
+/// ```compile_fail
+/// struct Handler;
+///
+/// #[chidori::handler(tick_millis = 100)]
+/// impl chidori::Handler<()> for Handler {
+///     fn handle_message(
+///         &mut self,
+///         _message: &chidori::message::Message<()>,
+///         _channel: &mut chidori::channel::MessageChannel,
+///     ) -> Result<(), &'static str> {
+///         Ok(())
+///     }
+/// }
+/// ```
+///
+/// And `tick_millis` is the only argument:
+///
+/// ```compile_fail
+/// struct Handler;
+///
+/// #[chidori::handler(tick = 100)]
+/// impl chidori::Handler<()> for Handler {
+///     fn handle_message(
+///         &mut self,
+///         _message: &chidori::message::Message<()>,
+///         _channel: &mut chidori::channel::MessageChannel,
+///     ) -> Result<(), &'static str> {
+///         Ok(())
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn handler(args: TokenStream, input: TokenStream) -> TokenStream {
+    let mut tick_millis: Option<syn::Expr> = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("tick_millis") {
+            tick_millis = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `tick_millis`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let item = parse_macro_input!(input as syn::ItemImpl);
+    expand_handler(item, tick_millis)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_handler(
+    mut item: syn::ItemImpl,
+    tick_millis: Option<syn::Expr>,
+) -> syn::Result<TokenStream2> {
+    let defines = |name: &str| {
+        item.items
+            .iter()
+            .any(|i| matches!(i, syn::ImplItem::Fn(f) if f.sig.ident == name))
+    };
+    let has_tick = defines("handle_tick");
+    let has_events = defines("send_events");
+
+    if let Some(tick_millis) = &tick_millis {
+        if has_events {
+            return Err(syn::Error::new_spanned(
+                tick_millis,
+                "`tick_millis` conflicts with the `send_events` defined here",
+            ));
+        }
+        if !has_tick {
+            return Err(syn::Error::new_spanned(
+                tick_millis,
+                "`tick_millis` needs a `handle_tick` to call",
+            ));
+        }
+    }
+
+    if !has_tick {
+        item.items.push(syn::parse_quote! {
+            fn handle_tick(
+                &mut self,
+                _channel: &mut ::chidori::channel::MessageChannel,
+            ) -> ::core::result::Result<(), &'static str> {
+                Ok(())
+            }
+        });
+    }
+    if !has_events {
+        item.items.push(match tick_millis {
+            Some(tick_millis) => syn::parse_quote! {
+                fn send_events(&self, send_channel: &::std::sync::mpsc::Sender<::chidori::Event>) {
+                    let send_channel = send_channel.clone();
+                    ::std::thread::spawn(move || loop {
+                        ::std::thread::sleep(::std::time::Duration::from_millis(#tick_millis));
+                        send_channel.send(::chidori::Event::Tick).unwrap();
+                    });
+                }
+            },
+            None => syn::parse_quote! {
+                fn send_events(&self, _send_channel: &::std::sync::mpsc::Sender<::chidori::Event>) {}
+            },
+        });
+    }
+    Ok(quote!(#item))
+}
@@ -0,0 +1,126 @@
+//! Adaptive gossip fanout and interval.
+//!
+//! Rounds that spread new values double the fanout and halve the interval, so a burst of
+//! broadcasts reaches everyone in a few quick rounds. Quiet rounds shrink the fanout by one and
+//! stretch the interval by half, settling into slow anti-entropy. The interval never drops
+//! below the smoothed round-trip time to peers, since gossiping faster than peers can report
+//! back what they have only sends values twice.
+
+use std::collections::HashMap;
+use std::time;
+
+/// Weight of a new sample in the smoothed round-trip time, as in TCP.
+const RTT_GAIN: f64 = 0.125;
+
+pub struct Controller {
+    min_fanout: usize,
+    max_fanout: usize,
+    min_interval: time::Duration,
+    max_interval: time::Duration,
+
+    fanout: usize,
+    interval: time::Duration,
+    fresh: usize,
+    last_round: time::Instant,
+
+    in_flight: HashMap<usize, (String, time::Instant)>,
+    /// Smoothed round-trip times in milliseconds.
+    rtts: HashMap<String, f64>,
+}
+
+impl Controller {
+    /// Creates a controller that starts idle, at `min_fanout` peers every `max_interval`.
+    pub fn new(
+        min_fanout: usize,
+        max_fanout: usize,
+        min_interval: time::Duration,
+        max_interval: time::Duration,
+    ) -> Self {
+        Self {
+            min_fanout,
+            max_fanout,
+            min_interval,
+            max_interval,
+            fanout: min_fanout,
+            interval: max_interval,
+            fresh: 0,
+            last_round: time::Instant::now(),
+            in_flight: HashMap::new(),
+            rtts: HashMap::new(),
+        }
+    }
+
+    pub fn fanout(&self) -> usize {
+        self.fanout
+    }
+
+    pub fn interval(&self) -> time::Duration {
+        self.interval
+    }
+
+    pub fn rtt(&self, peer: &str) -> Option<time::Duration> {
+        self.rtts
+            .get(peer)
+            .map(|ms| time::Duration::from_secs_f64(ms / 1000.0))
+    }
+
+    /// Records values that were new to this node since the last round.
+    pub fn fresh(&mut self, count: usize) {
+        self.fresh += count;
+        if count > 0 && self.interval > self.floor() {
+            // do not wait out a long idle interval before spreading them
+            self.interval = self.floor();
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_round.elapsed() >= self.interval
+    }
+
+    /// Starts a gossip round, returning the number of peers to gossip to.
+    pub fn round(&mut self) -> usize {
+        if self.fresh > 0 {
+            self.fanout = (self.fanout * 2).min(self.max_fanout);
+            self.interval = (self.interval / 2).max(self.floor());
+        } else {
+            self.fanout = self.fanout.saturating_sub(1).max(self.min_fanout);
+            self.interval = (self.interval * 3 / 2).clamp(self.floor(), self.max_interval);
+        }
+        self.fresh = 0;
+        self.last_round = time::Instant::now();
+
+        // replies may never come, forget about messages sent long ago
+        let expiry = self.max_interval * 4;
+        self.in_flight
+            .retain(|_, (_, sent)| sent.elapsed() < expiry);
+
+        self.fanout
+    }
+
+    /// Records a gossip message sent to `peer` whose reply will be timed.
+    pub fn sent(&mut self, peer: &str, msg_id: usize) {
+        self.in_flight
+            .insert(msg_id, (peer.to_string(), time::Instant::now()));
+    }
+
+    /// Records the reply to a message passed to `sent`.
+    pub fn acknowledged(&mut self, in_reply_to: usize) {
+        let Some((peer, sent)) = self.in_flight.remove(&in_reply_to) else {
+            return;
+        };
+        let sample = sent.elapsed().as_secs_f64() * 1000.0;
+        self.rtts
+            .entry(peer)
+            .and_modify(|rtt| *rtt += RTT_GAIN * (sample - *rtt))
+            .or_insert(sample);
+    }
+
+    /// The shortest interval, bounded by the mean round-trip time to peers.
+    fn floor(&self) -> time::Duration {
+        if self.rtts.is_empty() {
+            return self.min_interval;
+        }
+        let mean = self.rtts.values().sum::<f64>() / self.rtts.len() as f64;
+        time::Duration::from_secs_f64(mean / 1000.0).clamp(self.min_interval, self.max_interval)
+    }
+}
@@ -1,92 +1,83 @@
-use chidori;
 use chidori::channel;
 use chidori::message;
+use chidori::reliable;
+use chidori::router;
+use chidori::topology;
 use serde::Deserialize;
 use serde::Serialize;
 
-use std::collections::HashMap;
 use std::collections::HashSet;
 use std::io;
+use std::time;
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-#[serde(tag = "type")]
-enum Payload {
-    Broadcast {
-        message: i64,
-    },
-    BroadcastOk,
-    Read,
-    ReadOk {
-        messages: HashSet<i64>,
-    },
-    Topology {
-        topology: HashMap<String, Vec<String>>,
-    },
-    TopologyOk,
+const TICK_INTERVAL_MILLIS: u64 = 100;
+
+const INITIAL_RETRY_MILLIS: u64 = 200;
+const MAX_RETRY_MILLIS: u64 = 3000;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename = "broadcast")]
+struct Broadcast {
+    message: i64,
 }
 
-struct Handler {
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename = "broadcast_ok")]
+struct BroadcastOk {}
+
+struct Node {
     messages: HashSet<i64>,
-    topology: Option<HashMap<String, Vec<String>>>,
+    neighbors: Option<Vec<String>>,
+
+    reliable: reliable::Reliable<Broadcast>,
 }
 
-impl chidori::Handler<Payload> for Handler {
-    fn handle_message(
+impl Node {
+    fn broadcast(
         &mut self,
-        received: &message::Message<Payload>,
+        received: &message::Message<reliable::Sequenced<Broadcast>>,
         channel: &mut channel::MessageChannel,
     ) -> Result<(), &'static str> {
-        match &received.body.payload {
-            Payload::Broadcast { message } => {
-                if self.messages.insert(*message) {
-                    // new message, propagate
-                    let neighbors = self
-                        .topology
-                        .as_ref()
-                        .and_then(|t| t.get(&channel.node_id))
-                        .ok_or("unknown topology")?
-                        .clone();
-                    for neighbor in &neighbors {
-                        channel.send(
-                            neighbor,
-                            &Payload::Broadcast {
-                                message: message.clone(),
-                            },
-                        )?;
-                    }
-                }
-                channel.reply(received, &Payload::BroadcastOk)?
+        let message = received.body.payload.payload.message;
+        if !self.reliable.is_duplicate(received) && self.messages.insert(message) {
+            // new message, propagate
+            let neighbors = self.neighbors.clone().ok_or("unknown topology")?;
+            for neighbor in &neighbors {
+                self.reliable
+                    .send(channel, neighbor, Broadcast { message })?;
             }
-            Payload::Read => channel.reply(
-                received,
-                &Payload::ReadOk {
-                    messages: self.messages.clone(),
-                },
-            )?,
-            Payload::Topology { topology } => {
-                self.topology = Some(topology.clone());
-                channel.reply(received, &Payload::TopologyOk)?
-            }
-            _ => {}
         }
-        Ok(())
-    }
-
-    fn handle_tick(&mut self, _channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
-        // does nothing
-        Ok(())
-    }
-
-    fn send_events(&self, _send_channel: &std::sync::mpsc::Sender<chidori::Event>) {
-        // does nothing
+        channel.reply(received, &BroadcastOk {})
     }
 }
 
 fn main() -> io::Result<()> {
-    let mut handler = Handler {
+    let node = Node {
         messages: HashSet::new(),
-        topology: None,
+        neighbors: None,
+        reliable: reliable::Reliable::new(
+            time::Duration::from_millis(INITIAL_RETRY_MILLIS),
+            time::Duration::from_millis(MAX_RETRY_MILLIS),
+        ),
     };
-    chidori::main_loop(&mut handler)
+    let strategy = topology::Strategy::from_env().expect("invalid CHIDORI_TOPOLOGY");
+    let mut router = router::Router::new(node)
+        .with(router::topology(strategy, |node: &mut Node| {
+            &mut node.neighbors
+        }))
+        .with(router::read(|node: &Node| &node.messages))
+        .on("broadcast", Node::broadcast)
+        .on(
+            "broadcast_ok",
+            |node, received: &message::Message<BroadcastOk>, _| {
+                node.reliable.acknowledge(received);
+                Ok(())
+            },
+        )
+        // resend broadcasts that were lost, e.g. during a partition
+        .on_tick(
+            time::Duration::from_millis(TICK_INTERVAL_MILLIS),
+            |node, channel| node.reliable.tick(channel),
+        );
+    chidori::main_loop(&mut router)
 }
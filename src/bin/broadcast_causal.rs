@@ -0,0 +1,94 @@
+use chidori::causal;
+use chidori::channel;
+use chidori::message;
+use chidori::MaelstromPayload;
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::io;
+use std::time;
+
+const TICK_INTERVAL_MILLIS: u64 = 100;
+
+const SYNC_INTERVAL_MILLIS: u64 = 500;
+
+#[derive(MaelstromPayload, Clone)]
+enum Payload {
+    Broadcast {
+        message: i64,
+    },
+    BroadcastOk,
+    Read,
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Envelope {
+    Broadcast(Payload),
+    Causal(causal::Payload<i64>),
+}
+
+/// Reads return the messages in the order they were delivered, which respects causality.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<'a> {
+    messages: &'a [i64],
+}
+
+struct Handler {
+    causal: causal::Causal<i64>,
+    delivered: Vec<i64>,
+}
+
+#[chidori::handler(tick_millis = TICK_INTERVAL_MILLIS)]
+impl chidori::Handler<Envelope> for Handler {
+    fn handle_message(
+        &mut self,
+        received: &message::Message<Envelope>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let delivered = &mut self.delivered;
+        match &received.body.payload {
+            Envelope::Broadcast(payload @ Payload::Broadcast { message }) => {
+                self.causal
+                    .broadcast(*message, channel, |_, value| delivered.push(*value))?;
+                Payload::respond_broadcast(channel, &received.with_payload(payload.clone()))?
+            }
+            Envelope::Broadcast(Payload::Read) => channel.reply(
+                received,
+                &ReadOk {
+                    messages: delivered,
+                },
+            )?,
+            Envelope::Broadcast(payload @ Payload::Topology { .. }) => {
+                // every broadcast goes to every node
+                Payload::respond_topology(channel, &received.with_payload(payload.clone()))?
+            }
+            Envelope::Causal(payload) => {
+                self.causal
+                    .handle(&received.src, payload, channel, |_, value| {
+                        delivered.push(*value)
+                    })?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        self.causal.tick(channel)
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut handler = Handler {
+        causal: causal::Causal::new(time::Duration::from_millis(SYNC_INTERVAL_MILLIS)),
+        delivered: Vec::new(),
+    };
+    chidori::main_loop(&mut handler)
+}
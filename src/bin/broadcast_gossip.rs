@@ -1,19 +1,30 @@
-use chidori;
 use chidori::channel;
+use chidori::global_snapshot;
+use chidori::intset;
+use chidori::membership;
 use chidori::message;
+use chidori::topology;
+use chidori::version_vector;
 use chidori::Event;
-use rand::seq::SliceRandom;
 use serde::Deserialize;
 use serde::Serialize;
 
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::io;
 use std::sync::mpsc;
 use std::thread;
 use std::time;
 
-#[derive(Serialize, Deserialize)]
+const PROTOCOL_PERIOD_MILLIS: u64 = 1000;
+const NUM_INDIRECT_PROBES: usize = 3;
+const SNAPSHOT_RESEND_MILLIS: u64 = 200;
+const SNAPSHOT_TIMEOUT_MILLIS: u64 = 5000;
+/// The Maelstrom error code for a request that timed out.
+const TIMEOUT: u64 = 0;
+
+type Cut = global_snapshot::Cut<intset::IntSet, Vec<version_vector::Entries>>;
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 enum Payload {
@@ -22,81 +33,108 @@ enum Payload {
     },
     BroadcastOk,
     Read,
-    ReadOk {
-        messages: HashSet<i64>,
-    },
     Topology {
         topology: HashMap<String, Vec<String>>,
     },
     TopologyOk,
     // Custom message
     Gossip {
-        messages: HashSet<i64>,
+        versions: version_vector::VersionVector,
+        entries: Vec<version_vector::Entries>,
+        #[serde(default)]
+        membership: Vec<membership::Update>,
+        /// The snapshot epoch of the sender.
+        #[serde(default)]
+        epoch: u64,
+    },
+    /// Asks for a snapshot of the values at every node and in flight between them.
+    Snapshot,
+    SnapshotOk {
+        snapshot: Cut,
     },
+    Error {
+        code: u64,
+        text: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum Envelope {
+    Broadcast(Payload),
+    Membership(membership::Payload),
+    Snapshot(global_snapshot::Payload<intset::IntSet, Vec<version_vector::Entries>>),
+}
+
+/// Replies to reads borrow the set instead of cloning it into a `Payload`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<'a> {
+    messages: intset::Plain<'a>,
 }
 
 struct Handler {
-    messages: HashSet<i64>,
+    messages: version_vector::SequencedSet,
     topology: Option<HashMap<String, Vec<String>>>,
+    strategy: Option<topology::Strategy>,
+
+    known_by_dest: HashMap<String, version_vector::VersionVector>,
 
-    known_by_dest: HashMap<String, HashSet<i64>>,
+    membership: membership::Membership,
+
+    snapshots: global_snapshot::Snapshots<intset::IntSet, Vec<version_vector::Entries>>,
+    /// Snapshot requests waiting for every node to report.
+    snapshot_requests: HashMap<global_snapshot::SnapshotId, message::Message<Envelope>>,
 }
 
-impl chidori::Handler<Payload> for Handler {
+impl chidori::Handler<Envelope> for Handler {
     fn handle_message(
         &mut self,
-        received: &message::Message<Payload>,
+        received: &message::Message<Envelope>,
         channel: &mut channel::MessageChannel,
     ) -> Result<(), &'static str> {
         match &received.body.payload {
-            Payload::Broadcast { message } => {
-                self.messages.insert(*message);
-                channel.reply(received, &Payload::BroadcastOk)?
-            }
-            Payload::Read => channel.reply(
-                received,
-                &Payload::ReadOk {
-                    messages: self.messages.clone(),
-                },
-            )?,
-            Payload::Topology { topology } => {
-                self.topology = Some(topology.clone());
-                channel.reply(received, &Payload::TopologyOk)?
+            Envelope::Broadcast(payload) => self.handle_broadcast(received, payload, channel),
+            Envelope::Membership(payload) => {
+                self.membership.handle(&received.src, payload, channel)
             }
-            Payload::Gossip { messages } => {
-                self.messages.extend(messages);
-                self.known_by_dest
-                    .entry(received.src.clone())
-                    .or_default()
-                    .extend(messages);
-                // no reply
+            Envelope::Snapshot(payload) => {
+                let values = self.messages.values();
+                self.snapshots
+                    .handle(&received.src, payload, channel, || values.clone())?;
+                self.reply_snapshots(channel)
             }
-            _ => {}
         }
-        Ok(())
     }
 
     fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        self.membership.tick(channel)?;
+        self.snapshots.tick(channel)?;
+        self.reply_snapshots(channel)?;
+
         let Some(neighbors) = self.get_neighbors(&channel.node_id) else {
             // topology not yet received, do not gossip
-            return Ok(())
+            return Ok(());
         };
 
-        let mut rng = rand::thread_rng();
-
         for neighbor in neighbors {
-            let (known, mut unknown) = self.messages.iter().partition::<Vec<i64>, _>(|m| {
-                self.known_by_dest
-                    .entry(neighbor.clone())
-                    .or_default()
-                    .contains(m)
-            });
-            // TODO: Explanation
-            unknown.extend(known.as_slice().choose_multiple(&mut rng, 5));
+            if self.membership.state(&neighbor) == Some(membership::State::Dead) {
+                // membership keeps pinging it; once revived, it gets everything until it
+                // gossips back what it knows
+                self.known_by_dest.remove(&neighbor);
+                continue;
+            }
+            // send what the neighbor is missing, assuming it will receive it
+            let known = self.known_by_dest.entry(neighbor.clone()).or_default();
+            let entries = self.messages.missing(known);
+            version_vector::advance(known, &entries);
             channel.send(
                 &neighbor,
                 &Payload::Gossip {
-                    messages: HashSet::from_iter(unknown),
+                    versions: self.messages.version_vector(),
+                    entries,
+                    membership: self.membership.piggyback(),
+                    epoch: self.snapshots.send_epoch(&neighbor),
                 },
             )?;
         }
@@ -112,6 +150,80 @@ impl chidori::Handler<Payload> for Handler {
     }
 }
 impl Handler {
+    fn handle_broadcast(
+        &mut self,
+        received: &message::Message<Envelope>,
+        payload: &Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        match payload {
+            Payload::Broadcast { message } => {
+                self.messages.accept(&channel.node_id, *message);
+                channel.reply(received, &Payload::BroadcastOk)?
+            }
+            Payload::Read => channel.reply(
+                received,
+                &ReadOk {
+                    messages: self.messages.values().plain(),
+                },
+            )?,
+            Payload::Topology { topology } => {
+                // an overlay computed locally takes precedence over the suggested topology
+                let topology = match &self.strategy {
+                    Some(strategy) => strategy.compute(&channel.node_ids, topology),
+                    None => topology.clone(),
+                };
+                self.topology = Some(topology);
+                channel.reply(received, &Payload::TopologyOk)?
+            }
+            Payload::Gossip {
+                versions,
+                entries,
+                membership,
+                epoch,
+            } => {
+                let values = self.messages.values();
+                self.snapshots
+                    .observe(&received.src, *epoch, entries, channel, || values.clone())?;
+                self.membership.apply(membership, channel);
+                self.messages.apply(entries);
+                self.known_by_dest
+                    .insert(received.src.clone(), versions.clone());
+                // no reply
+                self.reply_snapshots(channel)?
+            }
+            Payload::Snapshot => {
+                let values = self.messages.values().clone();
+                let id = self.snapshots.start(values, channel)?;
+                self.snapshot_requests.insert(id, received.clone());
+                // a single node completes its snapshot right away
+                self.reply_snapshots(channel)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn reply_snapshots(
+        &mut self,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        for (id, cut) in self.snapshots.take_completed() {
+            let Some(request) = self.snapshot_requests.remove(&id) else {
+                continue;
+            };
+            let reply = match cut {
+                Ok(snapshot) => Payload::SnapshotOk { snapshot },
+                Err(text) => Payload::Error {
+                    code: TIMEOUT,
+                    text: text.to_string(),
+                },
+            };
+            channel.reply(&request, &reply)?;
+        }
+        Ok(())
+    }
+
     fn get_neighbors(&self, node_id: &str) -> Option<Vec<String>> {
         self.topology.as_ref().and_then(|t| t.get(node_id)).cloned()
     }
@@ -119,10 +231,22 @@ impl Handler {
 
 fn main() -> io::Result<()> {
     let mut handler = Handler {
-        messages: HashSet::new(),
+        messages: version_vector::SequencedSet::new(),
         topology: None,
+        strategy: topology::Strategy::from_env().expect("invalid CHIDORI_TOPOLOGY"),
 
         known_by_dest: HashMap::new(),
+
+        membership: membership::Membership::new(
+            time::Duration::from_millis(PROTOCOL_PERIOD_MILLIS),
+            NUM_INDIRECT_PROBES,
+        ),
+
+        snapshots: global_snapshot::Snapshots::new(
+            time::Duration::from_millis(SNAPSHOT_RESEND_MILLIS),
+            time::Duration::from_millis(SNAPSHOT_TIMEOUT_MILLIS),
+        ),
+        snapshot_requests: HashMap::new(),
     };
     chidori::main_loop(&mut handler)
 }
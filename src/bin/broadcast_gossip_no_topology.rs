@@ -1,22 +1,30 @@
-use chidori;
+use chidori::adaptive;
 use chidori::channel;
+use chidori::failure_detector;
+use chidori::intset;
 use chidori::message;
+use chidori::version_vector;
 use chidori::Event;
 use rand::seq::SliceRandom;
 use serde::Deserialize;
 use serde::Serialize;
 
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::io;
 use std::sync::mpsc;
 use std::thread;
 use std::time;
 
-const NUM_GOSSIP_PEERS: usize = 5;
-const NUM_NOTIFY_KNOWN: usize = 5;
+const MIN_GOSSIP_PEERS: usize = 2;
+const MAX_GOSSIP_PEERS: usize = 8;
 
-const TICK_INTERVAL_MILLIS: u64 = 200;
+const MIN_GOSSIP_INTERVAL_MILLIS: u64 = 50;
+const MAX_GOSSIP_INTERVAL_MILLIS: u64 = 1000;
+
+const TICK_INTERVAL_MILLIS: u64 = 25;
+
+const HEARTBEAT_INTERVAL_MILLIS: u64 = 1000;
+const PHI_THRESHOLD: f64 = 8.0;
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -27,23 +35,34 @@ enum Payload {
     },
     BroadcastOk,
     Read,
-    ReadOk {
-        messages: HashSet<i64>,
-    },
     Topology {
         topology: HashMap<String, Vec<String>>,
     },
     TopologyOk,
     // Custom message
     Gossip {
-        messages: HashSet<i64>,
+        versions: version_vector::VersionVector,
+        entries: Vec<version_vector::Entries>,
     },
+    GossipOk,
+    Heartbeat,
+}
+
+/// Replies to reads borrow the set instead of cloning it into a `Payload`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<'a> {
+    messages: intset::Plain<'a>,
 }
 
 struct Handler {
-    messages: HashSet<i64>,
+    messages: version_vector::SequencedSet,
+
+    known_by_dest: HashMap<String, version_vector::VersionVector>,
+
+    detector: failure_detector::FailureDetector,
 
-    known_by_dest: HashMap<String, HashSet<i64>>,
+    controller: adaptive::Controller,
 }
 
 impl chidori::Handler<Payload> for Handler {
@@ -54,55 +73,76 @@ impl chidori::Handler<Payload> for Handler {
     ) -> Result<(), &'static str> {
         match &received.body.payload {
             Payload::Broadcast { message } => {
-                self.messages.insert(*message);
+                if self.messages.accept(&channel.node_id, *message) {
+                    self.controller.fresh(1);
+                }
                 channel.reply(received, &Payload::BroadcastOk)?
             }
             Payload::Read => channel.reply(
                 received,
-                &Payload::ReadOk {
-                    messages: self.messages.clone(),
+                &ReadOk {
+                    messages: self.messages.values().plain(),
                 },
             )?,
             Payload::Topology { .. } => {
                 // ignore the topology
                 channel.reply(received, &Payload::TopologyOk)?
             }
-            Payload::Gossip { messages } => {
-                self.messages.extend(messages);
+            Payload::Gossip { versions, entries } => {
+                // gossip comes at a varying rate, only heartbeats are timed by the detector
+                let applied = self.messages.apply(entries);
+                self.controller.fresh(applied);
                 self.known_by_dest
-                    .entry(received.src.clone())
-                    .or_default()
-                    .extend(messages);
-                // no reply
+                    .insert(received.src.clone(), versions.clone());
+                // only gossip carrying values is timed, idle rounds need no reply
+                if !entries.is_empty() {
+                    channel.reply(received, &Payload::GossipOk)?
+                }
             }
+            Payload::GossipOk => {
+                if let Some(in_reply_to) = received.body.in_reply_to {
+                    self.controller.acknowledged(in_reply_to);
+                }
+            }
+            Payload::Heartbeat => self.detector.heartbeat(&received.src),
             _ => {}
         }
         Ok(())
     }
 
     fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        // suspected peers are skipped when picking gossip targets below
+        self.detector.tick(channel)?;
+
+        if !self.controller.is_due() {
+            return Ok(());
+        }
+        let fanout = self.controller.round();
         let mut rng = rand::thread_rng();
 
-        for neighbor in channel
+        // do not waste gossip on peers that are likely partitioned away
+        let peers: Vec<String> = channel
             .node_ids
-            .clone()
-            .as_slice()
-            .choose_multiple(&mut rng, NUM_GOSSIP_PEERS)
-        {
-            let (known, mut unknown) = self.messages.iter().partition::<Vec<i64>, _>(|m| {
-                self.known_by_dest
-                    .entry(neighbor.clone())
-                    .or_default()
-                    .contains(m)
-            });
-            // TODO: Explanation
-            unknown.extend(known.as_slice().choose_multiple(&mut rng, NUM_NOTIFY_KNOWN));
-            channel.send(
-                &neighbor,
-                &Payload::Gossip {
-                    messages: HashSet::from_iter(unknown),
-                },
-            )?;
+            .iter()
+            .filter(|n| !self.detector.is_suspected(n))
+            .cloned()
+            .collect();
+
+        for neighbor in peers.choose_multiple(&mut rng, fanout) {
+            // send what the neighbor is missing, assuming it will receive it
+            let known = self.known_by_dest.entry(neighbor.clone()).or_default();
+            let entries = self.messages.missing(known);
+            version_vector::advance(known, &entries);
+            let timed = !entries.is_empty();
+            let gossip = Payload::Gossip {
+                versions: self.messages.version_vector(),
+                entries,
+            };
+            let msg_id = channel.next_msg_id();
+            if timed {
+                self.controller.sent(neighbor, msg_id);
+            }
+            channel.send_with_id(neighbor, msg_id, &gossip)?;
         }
         Ok(())
     }
@@ -118,8 +158,18 @@ impl chidori::Handler<Payload> for Handler {
 
 fn main() -> io::Result<()> {
     let mut handler = Handler {
-        messages: HashSet::new(),
+        messages: version_vector::SequencedSet::new(),
         known_by_dest: HashMap::new(),
+        detector: failure_detector::FailureDetector::new(
+            time::Duration::from_millis(HEARTBEAT_INTERVAL_MILLIS),
+            PHI_THRESHOLD,
+        ),
+        controller: adaptive::Controller::new(
+            MIN_GOSSIP_PEERS,
+            MAX_GOSSIP_PEERS,
+            time::Duration::from_millis(MIN_GOSSIP_INTERVAL_MILLIS),
+            time::Duration::from_millis(MAX_GOSSIP_INTERVAL_MILLIS),
+        ),
     };
     chidori::main_loop(&mut handler)
 }
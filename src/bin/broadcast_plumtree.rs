@@ -0,0 +1,110 @@
+use chidori::channel;
+use chidori::intset;
+use chidori::message;
+use chidori::plumtree;
+use chidori::topology;
+use chidori::Event;
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+const TICK_INTERVAL_MILLIS: u64 = 50;
+
+const GRAFT_TIMEOUT_MILLIS: u64 = 300;
+const ANNOUNCE_INTERVAL_MILLIS: u64 = 2000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Broadcast {
+        message: i64,
+    },
+    BroadcastOk,
+    Read,
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Envelope {
+    Broadcast(Payload),
+    Plumtree(plumtree::Payload),
+}
+
+/// Replies to reads borrow the set instead of cloning it into a `Payload`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<'a> {
+    messages: intset::Plain<'a>,
+}
+
+struct Handler {
+    plumtree: plumtree::Plumtree,
+    strategy: Option<topology::Strategy>,
+}
+
+impl chidori::Handler<Envelope> for Handler {
+    fn handle_message(
+        &mut self,
+        received: &message::Message<Envelope>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        match &received.body.payload {
+            Envelope::Broadcast(Payload::Broadcast { message }) => {
+                self.plumtree.broadcast(*message, channel)?;
+                channel.reply(received, &Payload::BroadcastOk)?
+            }
+            Envelope::Broadcast(Payload::Read) => channel.reply(
+                received,
+                &ReadOk {
+                    messages: self.plumtree.values().plain(),
+                },
+            )?,
+            Envelope::Broadcast(Payload::Topology { topology }) => {
+                // an overlay computed locally takes precedence over the suggested topology
+                let topology = match &self.strategy {
+                    Some(strategy) => strategy.compute(&channel.node_ids, topology),
+                    None => topology.clone(),
+                };
+                let neighbors = topology.get(&channel.node_id).ok_or("unknown topology")?;
+                self.plumtree.set_peers(neighbors);
+                channel.reply(received, &Payload::TopologyOk)?
+            }
+            Envelope::Plumtree(payload) => self.plumtree.handle(&received.src, payload, channel)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        self.plumtree.tick(channel)
+    }
+
+    fn send_events(&self, send_channel: &mpsc::Sender<chidori::Event>) {
+        let send_channel = send_channel.clone();
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_millis(TICK_INTERVAL_MILLIS));
+            send_channel.send(Event::Tick).unwrap();
+        });
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut handler = Handler {
+        plumtree: plumtree::Plumtree::new(
+            time::Duration::from_millis(GRAFT_TIMEOUT_MILLIS),
+            time::Duration::from_millis(ANNOUNCE_INTERVAL_MILLIS),
+        ),
+        strategy: topology::Strategy::from_env().expect("invalid CHIDORI_TOPOLOGY"),
+    };
+    chidori::main_loop(&mut handler)
+}
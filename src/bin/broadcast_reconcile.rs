@@ -0,0 +1,140 @@
+use chidori::channel;
+use chidori::intset;
+use chidori::message;
+use chidori::reconcile;
+use chidori::Event;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::path;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+const NUM_RECONCILE_PEERS: usize = 3;
+
+const TICK_INTERVAL_MILLIS: u64 = 100;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Broadcast {
+        message: i64,
+    },
+    BroadcastOk,
+    Read,
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Envelope {
+    Broadcast(Payload),
+    Reconcile(reconcile::Payload),
+}
+
+/// Replies to reads borrow the set instead of cloning it into a `Payload`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<'a> {
+    messages: intset::Plain<'a>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Handler {
+    reconciler: reconcile::Reconciler,
+}
+
+impl chidori::Handler<Envelope> for Handler {
+    fn handle_message(
+        &mut self,
+        received: &message::Message<Envelope>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        match &received.body.payload {
+            Envelope::Broadcast(Payload::Broadcast { message }) => {
+                self.reconciler.insert(*message);
+                channel.reply(received, &Payload::BroadcastOk)?
+            }
+            Envelope::Broadcast(Payload::Read) => channel.reply(
+                received,
+                &ReadOk {
+                    messages: self.reconciler.values().plain(),
+                },
+            )?,
+            Envelope::Broadcast(Payload::Topology { .. }) => {
+                // ignore the topology
+                channel.reply(received, &Payload::TopologyOk)?
+            }
+            Envelope::Reconcile(payload) => {
+                self.reconciler.handle(&received.src, payload, channel)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        let mut rng = rand::thread_rng();
+
+        let peers: Vec<String> = channel
+            .node_ids
+            .iter()
+            .filter(|n| **n != channel.node_id)
+            .cloned()
+            .collect();
+
+        for peer in peers.choose_multiple(&mut rng, NUM_RECONCILE_PEERS) {
+            self.reconciler.start(peer, channel)?;
+        }
+        Ok(())
+    }
+
+    fn send_events(&self, send_channel: &mpsc::Sender<chidori::Event>) {
+        let ticks = send_channel.clone();
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_millis(TICK_INTERVAL_MILLIS));
+            ticks.send(Event::Tick).unwrap();
+        });
+
+        // ignored unless snapshots are enabled
+        if let Some(interval) = snapshot_interval() {
+            let snapshots = send_channel.clone();
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                snapshots.send(Event::Snapshot).unwrap();
+            });
+        }
+    }
+}
+
+/// How often to write a snapshot besides the ones asked for with `save_snapshot`, if at all.
+fn snapshot_interval() -> Option<time::Duration> {
+    let millis = env::var("CHIDORI_SNAPSHOT_INTERVAL_MILLIS").ok()?;
+    let millis = millis
+        .parse()
+        .expect("invalid CHIDORI_SNAPSHOT_INTERVAL_MILLIS");
+    Some(time::Duration::from_millis(millis))
+}
+
+fn main() -> io::Result<()> {
+    // fail at startup rather than once the handler is running
+    snapshot_interval();
+    let mut handler = Handler {
+        reconciler: reconcile::Reconciler::new(),
+    };
+    match env::var_os("CHIDORI_SNAPSHOT") {
+        Some(snapshot) => {
+            chidori::main_loop_with_snapshots(&mut handler, path::Path::new(&snapshot))
+        }
+        None => chidori::main_loop(&mut handler),
+    }
+}
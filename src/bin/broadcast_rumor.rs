@@ -0,0 +1,145 @@
+use chidori::channel;
+use chidori::intset;
+use chidori::message;
+use chidori::reconcile;
+use chidori::rumor;
+use chidori::Event;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+const TICK_INTERVAL_MILLIS: u64 = 50;
+
+const NUM_RUMOR_PEERS: usize = 3;
+/// Overridden by `CHIDORI_RUMOR_COOLING`.
+const COOLING: f64 = 0.25;
+
+const ANTI_ENTROPY_INTERVAL_MILLIS: u64 = 2000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Broadcast {
+        message: i64,
+    },
+    BroadcastOk,
+    Read,
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Envelope {
+    Broadcast(Payload),
+    Rumor(rumor::Payload),
+    Reconcile(reconcile::Payload),
+}
+
+/// Replies to reads borrow the set instead of cloning it into a `Payload`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<'a> {
+    messages: intset::Plain<'a>,
+}
+
+struct Handler {
+    rumors: rumor::Rumors,
+    reconciler: reconcile::Reconciler,
+
+    last_anti_entropy: time::Instant,
+}
+
+impl chidori::Handler<Envelope> for Handler {
+    fn handle_message(
+        &mut self,
+        received: &message::Message<Envelope>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        match &received.body.payload {
+            Envelope::Broadcast(Payload::Broadcast { message }) => {
+                if self.reconciler.insert(*message) {
+                    self.rumors.spread(*message);
+                }
+                channel.reply(received, &Payload::BroadcastOk)?
+            }
+            Envelope::Broadcast(Payload::Read) => channel.reply(
+                received,
+                &ReadOk {
+                    messages: self.reconciler.values().plain(),
+                },
+            )?,
+            Envelope::Broadcast(Payload::Topology { .. }) => {
+                // ignore the topology
+                channel.reply(received, &Payload::TopologyOk)?
+            }
+            Envelope::Rumor(payload) => {
+                let reconciler = &mut self.reconciler;
+                self.rumors
+                    .handle(&received.src, payload, channel, |v| reconciler.insert(v))?
+            }
+            // values found by anti-entropy are not spread as rumors, they are late already
+            Envelope::Reconcile(payload) => {
+                self.reconciler.handle(&received.src, payload, channel)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        let peers: Vec<String> = channel
+            .node_ids
+            .iter()
+            .filter(|n| **n != channel.node_id)
+            .cloned()
+            .collect();
+
+        self.rumors.tick(&peers, channel)?;
+
+        if self.last_anti_entropy.elapsed()
+            >= time::Duration::from_millis(ANTI_ENTROPY_INTERVAL_MILLIS)
+        {
+            self.last_anti_entropy = time::Instant::now();
+            if let Some(peer) = peers.choose(&mut rand::thread_rng()) {
+                self.reconciler.start(peer, channel)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_events(&self, send_channel: &mpsc::Sender<chidori::Event>) {
+        let send_channel = send_channel.clone();
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_millis(TICK_INTERVAL_MILLIS));
+            send_channel.send(Event::Tick).unwrap();
+        });
+    }
+}
+
+fn main() -> io::Result<()> {
+    let cooling = match env::var("CHIDORI_RUMOR_COOLING") {
+        Ok(value) => value
+            .parse()
+            .ok()
+            .filter(|p| (0.0..=1.0).contains(p))
+            .expect("invalid CHIDORI_RUMOR_COOLING"),
+        Err(_) => COOLING,
+    };
+    let mut handler = Handler {
+        rumors: rumor::Rumors::new(NUM_RUMOR_PEERS, cooling),
+        reconciler: reconcile::Reconciler::new(),
+        last_anti_entropy: time::Instant::now(),
+    };
+    chidori::main_loop(&mut handler)
+}
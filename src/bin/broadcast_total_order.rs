@@ -0,0 +1,172 @@
+use chidori::channel;
+use chidori::election;
+use chidori::kv;
+use chidori::message;
+use chidori::total_order;
+use chidori::total_order::TotalOrder;
+use chidori::Event;
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::str;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+const TICK_INTERVAL_MILLIS: u64 = 50;
+
+const RESUBMIT_INTERVAL_MILLIS: u64 = 500;
+const ELECTION_TIMEOUT_MILLIS: u64 = 1000;
+const HEARTBEAT_INTERVAL_MILLIS: u64 = 100;
+const KV_TIMEOUT_MILLIS: u64 = 1000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Broadcast {
+        message: i64,
+    },
+    BroadcastOk,
+    Read,
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Envelope {
+    Broadcast(Payload),
+    Order(total_order::Payload<i64>),
+}
+
+/// Reads return the messages in delivery order, which is the same on every node.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<'a> {
+    messages: &'a [i64],
+}
+
+enum Backend {
+    /// The first node numbers every message.
+    Sequencer,
+    Elected,
+    Consensus,
+}
+
+impl str::FromStr for Backend {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequencer" => Ok(Backend::Sequencer),
+            "elected" => Ok(Backend::Elected),
+            "consensus" => Ok(Backend::Consensus),
+            _ => Err("unknown total order backend"),
+        }
+    }
+}
+
+struct Handler {
+    backend: Backend,
+    order: Option<Box<dyn TotalOrder<i64>>>,
+    delivered: Vec<i64>,
+}
+
+impl Handler {
+    fn order(
+        &mut self,
+        channel: &channel::MessageChannel,
+    ) -> Result<&mut dyn TotalOrder<i64>, &'static str> {
+        // the fixed sequencer is only known once the node ids are
+        if self.order.is_none() {
+            let resubmit_interval = time::Duration::from_millis(RESUBMIT_INTERVAL_MILLIS);
+            let order: Box<dyn TotalOrder<i64>> = match self.backend {
+                Backend::Sequencer => {
+                    let sequencer = channel.node_ids.iter().min().ok_or("no nodes")?;
+                    Box::new(total_order::Sequencer::fixed(sequencer, resubmit_interval))
+                }
+                Backend::Elected => Box::new(total_order::Sequencer::elected(
+                    election::Election::new(
+                        time::Duration::from_millis(ELECTION_TIMEOUT_MILLIS),
+                        time::Duration::from_millis(HEARTBEAT_INTERVAL_MILLIS),
+                    ),
+                    resubmit_interval,
+                )),
+                Backend::Consensus => Box::new(total_order::Consensus::new(
+                    kv::LIN_KV,
+                    time::Duration::from_millis(KV_TIMEOUT_MILLIS),
+                )),
+            };
+            self.order = Some(order);
+        }
+        Ok(self.order.as_deref_mut().unwrap())
+    }
+}
+
+impl chidori::Handler<Envelope> for Handler {
+    fn handle_message(
+        &mut self,
+        received: &message::Message<Envelope>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        match &received.body.payload {
+            Envelope::Broadcast(Payload::Broadcast { message }) => {
+                self.order(channel)?.broadcast(*message, channel)?;
+                channel.reply(received, &Payload::BroadcastOk)?
+            }
+            Envelope::Broadcast(Payload::Read) => channel.reply(
+                received,
+                &ReadOk {
+                    messages: &self.delivered,
+                },
+            )?,
+            Envelope::Broadcast(Payload::Topology { .. }) => {
+                // ignore the topology
+                channel.reply(received, &Payload::TopologyOk)?
+            }
+            Envelope::Order(payload) => self.order(channel)?.handle(
+                &received.src,
+                received.body.in_reply_to,
+                payload,
+                channel,
+            )?,
+            _ => {}
+        }
+        let delivered = self.order(channel)?.take_delivered();
+        self.delivered.extend(delivered);
+        Ok(())
+    }
+
+    fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        let order = self.order(channel)?;
+        order.tick(channel)?;
+        let delivered = order.take_delivered();
+        self.delivered.extend(delivered);
+        Ok(())
+    }
+
+    fn send_events(&self, send_channel: &mpsc::Sender<chidori::Event>) {
+        let send_channel = send_channel.clone();
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_millis(TICK_INTERVAL_MILLIS));
+            send_channel.send(Event::Tick).unwrap();
+        });
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut handler = Handler {
+        backend: env::var("CHIDORI_TOTAL_ORDER")
+            .map_or(Ok(Backend::Sequencer), |backend| backend.parse())
+            .expect("invalid CHIDORI_TOTAL_ORDER"),
+        order: None,
+        delivered: Vec::new(),
+    };
+    chidori::main_loop(&mut handler)
+}
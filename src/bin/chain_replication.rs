@@ -0,0 +1,847 @@
+use chidori::channel;
+use chidori::kv;
+use chidori::message;
+use chidori::Event;
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+const TICK_INTERVAL_MILLIS: u64 = 100;
+const SUSPECT_TIMEOUT_MILLIS: u64 = 1000;
+/// Requests the head or tail did not answer in time are sent again.
+const RESEND_TIMEOUT_MILLIS: u64 = 1000;
+const KV_TIMEOUT_MILLIS: u64 = 1000;
+/// How often the configuration is read when there is nothing else to learn it from.
+const CONFIG_POLL_MILLIS: u64 = 500;
+
+const CONFIG_KEY: &str = "chain-replication/config";
+
+/// How many committed ops are remembered, to recognize resubmissions after a failover.
+const RECENT_OPS: usize = 10_000;
+
+const ERROR_KEY_DOES_NOT_EXIST: u32 = 20;
+const ERROR_PRECONDITION_FAILED: u32 = 22;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "kind")]
+enum Op {
+    Write { key: i64, value: i64 },
+    Cas { key: i64, from: i64, to: i64 },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Read {
+        key: i64,
+    },
+    ReadOk {
+        value: i64,
+    },
+    Write {
+        key: i64,
+        value: i64,
+    },
+    WriteOk,
+    Cas {
+        key: i64,
+        from: i64,
+        to: i64,
+    },
+    CasOk,
+    Error {
+        code: u32,
+        text: String,
+    },
+    Stats,
+    StatsOk {
+        ops: usize,
+        messages: usize,
+        heartbeats: usize,
+        messages_per_op: f64,
+    },
+    // Custom messages
+    ChainSubmit {
+        origin: String,
+        op_id: usize,
+        op: Op,
+    },
+    ChainWrite {
+        seq: usize,
+        origin: String,
+        op_id: usize,
+        key: i64,
+        value: i64,
+    },
+    ChainAck {
+        seq: usize,
+    },
+    ChainFailed {
+        op_id: usize,
+        code: u32,
+        text: String,
+    },
+    ChainRead {
+        origin: String,
+        op_id: usize,
+        key: i64,
+    },
+    ChainReadOk {
+        op_id: usize,
+        value: Option<i64>,
+    },
+    /// Tells the origin of a resubmitted op that it was committed already.
+    ChainCommitted {
+        op_id: usize,
+    },
+    ChainHeartbeat,
+}
+
+/// Client requests are `Payload`s, replies from lin-kv are told apart by their sender.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum Envelope {
+    Chain(Payload),
+    Kv(kv::Payload),
+}
+
+/// The chain as agreed on in lin-kv. Version 0, every node, is never written.
+///
+/// A node that suspects a member failed proposes the chain without it by compare-and-set, so
+/// that every node moves through the same versions. Nodes only take writes from their
+/// predecessor and acks from their successor, and the tail serves reads only once a read of the
+/// configuration, sent after the reads arrived, shows it is still the tail. Removed nodes do
+/// not rejoin, they keep forwarding client requests to the chain.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct Config {
+    version: u64,
+    nodes: Vec<String>,
+}
+
+/// A read waiting at the tail for the configuration to be confirmed.
+struct Read {
+    origin: String,
+    op_id: usize,
+    key: i64,
+}
+
+enum KvRequest {
+    Read,
+    Propose(Config),
+}
+
+struct KvInFlight {
+    msg_id: usize,
+    request: KvRequest,
+    sent: time::Instant,
+    /// Reads that arrived before this request was sent, served once it confirms the chain.
+    reads: Vec<Read>,
+}
+
+/// A write that was forwarded down the chain but not yet acknowledged by the tail.
+#[derive(Clone)]
+struct Sent {
+    origin: String,
+    op_id: usize,
+    key: i64,
+    value: i64,
+}
+
+/// A client request received by this node, and the head or tail it was sent to.
+struct Pending {
+    request: message::Message<Envelope>,
+    sent_to: String,
+    sent_at: time::Instant,
+}
+
+struct Handler {
+    store: HashMap<i64, i64>,
+
+    /// Sequence number of the last write applied locally, assigned by the head.
+    applied_seq: usize,
+    sent: BTreeMap<usize, Sent>,
+    /// When the successor last acknowledged a write, or was sent one with none outstanding.
+    acked_at: time::Instant,
+    /// Writes that arrived before an earlier one, waiting to be applied in order.
+    reordered: BTreeMap<usize, Sent>,
+    /// Origin and op id of the latest committed writes.
+    recent: VecDeque<(String, usize)>,
+
+    /// Client requests received by this node, waiting for the chain to complete them.
+    pending: HashMap<usize, Pending>,
+    next_op_id: usize,
+
+    last_heard: HashMap<String, time::Instant>,
+    config: Config,
+    kv: Option<KvInFlight>,
+    config_read: time::Instant,
+    /// Reads at the tail that no configuration read was sent for yet.
+    reads: Vec<Read>,
+
+    ops: usize,
+    messages: usize,
+    heartbeats: usize,
+}
+
+impl chidori::Handler<Envelope> for Handler {
+    fn handle_message(
+        &mut self,
+        received: &message::Message<Envelope>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        self.join(channel);
+        if received.src == kv::LIN_KV {
+            // replies from lin-kv share their types with client requests
+            let payload = serde_json::to_value(&received.body.payload)
+                .and_then(serde_json::from_value)
+                .map_err(|_| "invalid lin-kv reply")?;
+            return self.handle_kv(received.body.in_reply_to, &payload, channel);
+        }
+        self.last_heard
+            .insert(received.src.clone(), time::Instant::now());
+
+        let Envelope::Chain(payload) = &received.body.payload else {
+            return Ok(());
+        };
+        match payload {
+            Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. } => {
+                let op_id = self.register(received);
+                self.dispatch(op_id, channel)?;
+            }
+            Payload::Stats => {
+                let messages_per_op = if self.ops == 0 {
+                    0.0
+                } else {
+                    self.messages as f64 / self.ops as f64
+                };
+                channel.reply(
+                    received,
+                    &Payload::StatsOk {
+                        ops: self.ops,
+                        messages: self.messages,
+                        heartbeats: self.heartbeats,
+                        messages_per_op,
+                    },
+                )?
+            }
+            // an origin that does not know the head yet sends again once it does
+            Payload::ChainSubmit { origin, op_id, op } if self.head() == channel.node_id => {
+                self.apply_at_head(origin, *op_id, op, channel)?
+            }
+            // a removed predecessor may still think it is part of the chain
+            Payload::ChainWrite {
+                seq,
+                origin,
+                op_id,
+                key,
+                value,
+            } if self.predecessor(channel).as_ref() == Some(&received.src) => {
+                let sent = Sent {
+                    origin: origin.clone(),
+                    op_id: *op_id,
+                    key: *key,
+                    value: *value,
+                };
+                self.apply_write(*seq, sent, channel)?
+            }
+            Payload::ChainAck { seq }
+                if self.successor(channel).as_ref() == Some(&received.src) =>
+            {
+                self.acknowledge(*seq, channel)?
+            }
+            Payload::ChainFailed { op_id, code, text } => {
+                self.complete_error(*op_id, *code, text, channel)?
+            }
+            Payload::ChainRead { origin, op_id, key } if self.tail() == channel.node_id => {
+                self.reads.push(Read {
+                    origin: origin.clone(),
+                    op_id: *op_id,
+                    key: *key,
+                });
+                self.read_config(channel)?
+            }
+            Payload::ChainReadOk { op_id, value } => self.complete_read(*op_id, *value, channel)?,
+            Payload::ChainCommitted { op_id } => self.complete_write(*op_id, channel)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        self.join(channel);
+        for node in channel.node_ids.clone() {
+            if node != channel.node_id {
+                self.heartbeats += 1;
+                channel.send(&node, &Payload::ChainHeartbeat)?;
+            }
+        }
+
+        let kv_timeout = time::Duration::from_millis(KV_TIMEOUT_MILLIS);
+        if self
+            .kv
+            .as_ref()
+            .is_some_and(|kv| kv.sent.elapsed() >= kv_timeout)
+        {
+            let kv = self.kv.take().unwrap();
+            self.reads.extend(kv.reads);
+        }
+
+        // propose the chain without the members suspected failed
+        let timeout = time::Duration::from_millis(SUSPECT_TIMEOUT_MILLIS);
+        let alive: Vec<String> = self
+            .config
+            .nodes
+            .iter()
+            .filter(|n| {
+                **n == channel.node_id
+                    || self
+                        .last_heard
+                        .get(*n)
+                        .is_none_or(|heard| heard.elapsed() < timeout)
+            })
+            .cloned()
+            .collect();
+        let member = self.config.nodes.contains(&channel.node_id);
+        if member && alive.len() < self.config.nodes.len() {
+            let config = Config {
+                version: self.config.version + 1,
+                nodes: alive,
+            };
+            self.propose(config, channel)?;
+        }
+        let poll = time::Duration::from_millis(CONFIG_POLL_MILLIS);
+        if !self.reads.is_empty() || self.config_read.elapsed() >= poll {
+            self.read_config(channel)?;
+        }
+
+        // a write or ack was lost on the way, and later writes wait for it down the chain
+        let resend = time::Duration::from_millis(RESEND_TIMEOUT_MILLIS);
+        if let Some(successor) = self.successor(channel) {
+            if !self.sent.is_empty() && self.acked_at.elapsed() >= resend {
+                self.acked_at = time::Instant::now();
+                for (seq, sent) in self.sent.clone() {
+                    self.forward(seq, &sent, &successor, channel)?;
+                }
+            }
+        }
+
+        // requests sent to a head or tail that failed, or that were lost, would never complete
+        let head = self.head();
+        let tail = self.tail();
+        let stale: Vec<usize> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| {
+                let target = match pending.request.body.payload {
+                    Envelope::Chain(Payload::Read { .. }) => &tail,
+                    _ => &head,
+                };
+                pending.sent_to != *target || pending.sent_at.elapsed() >= resend
+            })
+            .map(|(op_id, _)| *op_id)
+            .collect();
+        for op_id in stale {
+            self.dispatch(op_id, channel)?;
+        }
+        Ok(())
+    }
+
+    fn send_events(&self, send_channel: &mpsc::Sender<chidori::Event>) {
+        let send_channel = send_channel.clone();
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_millis(TICK_INTERVAL_MILLIS));
+            send_channel.send(Event::Tick).unwrap();
+        });
+    }
+}
+
+impl Handler {
+    /// Starts from the chain of every node, until lin-kv tells otherwise.
+    fn join(&mut self, channel: &channel::MessageChannel) {
+        if self.config.nodes.is_empty() {
+            let mut nodes = channel.node_ids.clone();
+            nodes.sort();
+            self.config.nodes = nodes;
+        }
+    }
+
+    fn head(&self) -> String {
+        self.config.nodes.first().unwrap().clone()
+    }
+
+    fn tail(&self) -> String {
+        self.config.nodes.last().unwrap().clone()
+    }
+
+    fn neighbor(&self, channel: &channel::MessageChannel, offset: isize) -> Option<String> {
+        let chain = &self.config.nodes;
+        let position = chain.iter().position(|n| *n == channel.node_id)?;
+        let index = position.checked_add_signed(offset)?;
+        chain.get(index).cloned()
+    }
+
+    fn predecessor(&self, channel: &channel::MessageChannel) -> Option<String> {
+        self.neighbor(channel, -1)
+    }
+
+    fn successor(&self, channel: &channel::MessageChannel) -> Option<String> {
+        self.neighbor(channel, 1)
+    }
+
+    fn send(
+        &mut self,
+        channel: &mut channel::MessageChannel,
+        dest: &str,
+        payload: &Payload,
+    ) -> Result<(), &'static str> {
+        self.messages += 1;
+        channel.send(dest, payload)
+    }
+
+    fn send_kv(
+        &mut self,
+        request: KvRequest,
+        payload: &kv::Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        self.messages += 1;
+        let msg_id = channel.next_msg_id();
+        self.kv = Some(KvInFlight {
+            msg_id,
+            request,
+            sent: time::Instant::now(),
+            reads: std::mem::take(&mut self.reads),
+        });
+        channel.send_with_id(kv::LIN_KV, msg_id, payload)
+    }
+
+    /// Reads the configuration, unless lin-kv is busy with another request. Reads waiting at
+    /// the tail are served once it answers.
+    fn read_config(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if self.kv.is_some() {
+            return Ok(());
+        }
+        self.config_read = time::Instant::now();
+        let read = kv::Payload::Read {
+            key: CONFIG_KEY.into(),
+        };
+        self.send_kv(KvRequest::Read, &read, channel)
+    }
+
+    fn propose(
+        &mut self,
+        config: Config,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        if self.kv.is_some() {
+            return Ok(());
+        }
+        let to_value = |config| serde_json::to_value(config).map_err(|_| "invalid config");
+        let cas = kv::Payload::Cas {
+            key: CONFIG_KEY.into(),
+            from: to_value(&self.config)?,
+            to: to_value(&config)?,
+            // version 0 is never written
+            create_if_not_exists: self.config.version == 0,
+        };
+        self.send_kv(KvRequest::Propose(config), &cas, channel)
+    }
+
+    fn handle_kv(
+        &mut self,
+        in_reply_to: Option<usize>,
+        payload: &kv::Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        if in_reply_to.is_none() || self.kv.as_ref().map(|kv| kv.msg_id) != in_reply_to {
+            return Ok(());
+        }
+        let kv = self.kv.take().unwrap();
+        let proposed = matches!(kv.request, KvRequest::Propose(_));
+
+        let confirmed = match (kv.request, payload) {
+            (KvRequest::Propose(config), kv::Payload::CasOk) => {
+                self.adopt(config, channel)?;
+                true
+            }
+            (KvRequest::Read, kv::Payload::ReadOk { value }) => {
+                let config = serde_json::from_value(value.clone()).map_err(|_| "invalid config")?;
+                self.adopt(config, channel)?;
+                true
+            }
+            // nothing was removed yet
+            (KvRequest::Read, kv::Payload::Error { code, .. }) => *code == kv::KEY_DOES_NOT_EXIST,
+            // a failed proposal means someone else changed the chain first
+            _ => false,
+        };
+
+        if !confirmed {
+            self.reads.extend(kv.reads);
+        } else if self.tail() == channel.node_id {
+            // the chain is current as of after these reads arrived
+            for read in kv.reads {
+                let value = self.store.get(&read.key).copied();
+                if read.origin == channel.node_id {
+                    self.complete_read(read.op_id, value, channel)?;
+                } else {
+                    let read_ok = Payload::ChainReadOk {
+                        op_id: read.op_id,
+                        value,
+                    };
+                    self.send(channel, &read.origin, &read_ok)?;
+                }
+            }
+        }
+        // otherwise their origins send them again to the new tail
+
+        if (proposed && !confirmed) || !self.reads.is_empty() {
+            self.read_config(channel)?;
+        }
+        Ok(())
+    }
+
+    /// Moves to a newer configuration, passing on what the new neighbors may have missed.
+    fn adopt(
+        &mut self,
+        config: Config,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        if config.version <= self.config.version {
+            return Ok(());
+        }
+        let was_head = self.head() == channel.node_id;
+        let successor = self.successor(channel);
+        self.config = config;
+        if !self.config.nodes.contains(&channel.node_id) {
+            return Ok(());
+        }
+
+        if !was_head && self.head() == channel.node_id {
+            // writes waiting on one the removed head never passed on are not committed
+            // anywhere, their origins submit them again
+            self.reordered.clear();
+        }
+        let new_successor = self.successor(channel);
+        if new_successor != successor {
+            match new_successor {
+                Some(successor) => {
+                    // the new successor may have missed writes still in flight
+                    for (seq, sent) in self.sent.clone() {
+                        self.forward(seq, &sent, &successor, channel)?;
+                    }
+                }
+                None => {
+                    // we became the tail, everything we forwarded is now committed
+                    if let Some(&seq) = self.sent.keys().next_back() {
+                        self.acknowledge(seq, channel)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, received: &message::Message<Envelope>) -> usize {
+        let op_id = self.next_op_id;
+        self.next_op_id += 1;
+        self.ops += 1;
+        self.pending.insert(
+            op_id,
+            Pending {
+                request: received.clone(),
+                sent_to: String::new(),
+                sent_at: time::Instant::now(),
+            },
+        );
+        op_id
+    }
+
+    /// Sends a pending request where it is served: reads to the tail, writes to the head.
+    fn dispatch(
+        &mut self,
+        op_id: usize,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let Some(pending) = self.pending.get_mut(&op_id) else {
+            return Ok(());
+        };
+        let Envelope::Chain(request) = pending.request.body.payload.clone() else {
+            return Ok(());
+        };
+        let target = match request {
+            Payload::Read { .. } => self.config.nodes.last().unwrap().clone(),
+            _ => self.config.nodes.first().unwrap().clone(),
+        };
+        pending.sent_to = target.clone();
+        pending.sent_at = time::Instant::now();
+
+        let origin = channel.node_id.clone();
+        let op = match request {
+            Payload::Read { key } if target == origin => {
+                self.reads.push(Read { origin, op_id, key });
+                return self.read_config(channel);
+            }
+            Payload::Read { key } => {
+                let read = Payload::ChainRead { origin, op_id, key };
+                return self.send(channel, &target, &read);
+            }
+            Payload::Write { key, value } => Op::Write { key, value },
+            Payload::Cas { key, from, to } => Op::Cas { key, from, to },
+            _ => return Ok(()),
+        };
+        if target == origin {
+            self.apply_at_head(&origin, op_id, &op, channel)
+        } else {
+            self.send(
+                channel,
+                &target,
+                &Payload::ChainSubmit { origin, op_id, op },
+            )
+        }
+    }
+
+    /// Orders a write at the head of the chain, evaluating compare-and-set against the head's
+    /// state since it has seen every write before any other node.
+    fn apply_at_head(
+        &mut self,
+        origin: &str,
+        op_id: usize,
+        op: &Op,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        // a resubmission, of a write this node has applied already
+        let same_op = |o: &str, id: usize| o == origin && id == op_id;
+        if self.sent.values().any(|s| same_op(&s.origin, s.op_id)) {
+            // still in flight, forwarded again on tick until the tail acknowledges it
+            return Ok(());
+        }
+        if self.recent.iter().any(|(o, id)| same_op(o, *id)) {
+            return if origin == channel.node_id {
+                self.complete_write(op_id, channel)
+            } else {
+                self.send(channel, origin, &Payload::ChainCommitted { op_id })
+            };
+        }
+
+        let (key, value) = match *op {
+            Op::Write { key, value } => (key, value),
+            Op::Cas { key, from, to } => match self.store.get(&key) {
+                Some(current) if *current == from => (key, to),
+                current => {
+                    let (code, text) = match current {
+                        Some(_) => (ERROR_PRECONDITION_FAILED, "value does not match"),
+                        None => (ERROR_KEY_DOES_NOT_EXIST, "key does not exist"),
+                    };
+                    return if origin == channel.node_id {
+                        self.complete_error(op_id, code, text, channel)
+                    } else {
+                        self.send(
+                            channel,
+                            origin,
+                            &Payload::ChainFailed {
+                                op_id,
+                                code,
+                                text: text.to_string(),
+                            },
+                        )
+                    };
+                }
+            },
+        };
+
+        let seq = self.applied_seq + 1;
+        let sent = Sent {
+            origin: origin.to_string(),
+            op_id,
+            key,
+            value,
+        };
+        self.apply_write(seq, sent, channel)
+    }
+    fn apply_write(
+        &mut self,
+        seq: usize,
+        sent: Sent,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        if seq > self.applied_seq + 1 {
+            // an earlier write is still on its way
+            self.reordered.insert(seq, sent);
+            return Ok(());
+        }
+        self.append(seq, sent, channel)?;
+        while let Some(sent) = self.reordered.remove(&(self.applied_seq + 1)) {
+            self.append(self.applied_seq + 1, sent, channel)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the next write, or one applied before, and passes it down the chain.
+    fn append(
+        &mut self,
+        seq: usize,
+        sent: Sent,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        // writes are resent on reconfiguration, only apply them once
+        if seq > self.applied_seq {
+            self.applied_seq = seq;
+            self.store.insert(sent.key, sent.value);
+        }
+
+        match self.successor(channel) {
+            Some(successor) => {
+                self.forward(seq, &sent, &successor, channel)?;
+                if self.sent.is_empty() {
+                    self.acked_at = time::Instant::now();
+                }
+                self.sent.insert(seq, sent);
+                Ok(())
+            }
+            None => {
+                self.sent.insert(seq, sent);
+                self.acknowledge(seq, channel)
+            }
+        }
+    }
+
+    fn forward(
+        &mut self,
+        seq: usize,
+        sent: &Sent,
+        successor: &str,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        self.send(
+            channel,
+            successor,
+            &Payload::ChainWrite {
+                seq,
+                origin: sent.origin.clone(),
+                op_id: sent.op_id,
+                key: sent.key,
+                value: sent.value,
+            },
+        )
+    }
+
+    /// Handles an acknowledgement flowing back from the tail: every write up to `seq` is
+    /// committed, so clients waiting on this node get their reply.
+    fn acknowledge(
+        &mut self,
+        seq: usize,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let remaining = self.sent.split_off(&(seq + 1));
+        let committed = std::mem::replace(&mut self.sent, remaining);
+        if committed.is_empty() {
+            return Ok(());
+        }
+        self.acked_at = time::Instant::now();
+
+        for sent in committed.values() {
+            self.recent.push_back((sent.origin.clone(), sent.op_id));
+            if self.recent.len() > RECENT_OPS {
+                self.recent.pop_front();
+            }
+            if sent.origin == channel.node_id {
+                self.complete_write(sent.op_id, channel)?;
+            }
+        }
+
+        match self.predecessor(channel) {
+            Some(predecessor) => self.send(channel, &predecessor, &Payload::ChainAck { seq }),
+            None => Ok(()),
+        }
+    }
+
+    fn complete_write(
+        &mut self,
+        op_id: usize,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let Some(pending) = self.pending.remove(&op_id) else {
+            return Ok(());
+        };
+        let reply = match pending.request.body.payload {
+            Envelope::Chain(Payload::Cas { .. }) => Payload::CasOk,
+            _ => Payload::WriteOk,
+        };
+        channel.reply(&pending.request, &reply)
+    }
+
+    fn complete_read(
+        &mut self,
+        op_id: usize,
+        value: Option<i64>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let Some(pending) = self.pending.remove(&op_id) else {
+            return Ok(());
+        };
+        let request = pending.request;
+        match value {
+            Some(value) => channel.reply(&request, &Payload::ReadOk { value }),
+            None => channel.reply(
+                &request,
+                &Payload::Error {
+                    code: ERROR_KEY_DOES_NOT_EXIST,
+                    text: "key does not exist".to_string(),
+                },
+            ),
+        }
+    }
+
+    fn complete_error(
+        &mut self,
+        op_id: usize,
+        code: u32,
+        text: &str,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let Some(pending) = self.pending.remove(&op_id) else {
+            return Ok(());
+        };
+        channel.reply(
+            &pending.request,
+            &Payload::Error {
+                code,
+                text: text.to_string(),
+            },
+        )
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut handler = Handler {
+        store: HashMap::new(),
+        applied_seq: 0,
+        sent: BTreeMap::new(),
+        acked_at: time::Instant::now(),
+        reordered: BTreeMap::new(),
+        recent: VecDeque::new(),
+        pending: HashMap::new(),
+        next_op_id: 0,
+        last_heard: HashMap::new(),
+        config: Config {
+            version: 0,
+            nodes: Vec::new(),
+        },
+        kv: None,
+        config_read: time::Instant::now(),
+        reads: Vec::new(),
+        ops: 0,
+        messages: 0,
+        heartbeats: 0,
+    };
+    chidori::main_loop(&mut handler)
+}
@@ -1,14 +1,10 @@
-use chidori;
 use chidori::channel;
 use chidori::message;
-use serde::Deserialize;
-use serde::Serialize;
+use chidori::MaelstromPayload;
 
 use std::io;
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-#[serde(tag = "type")]
+#[derive(MaelstromPayload)]
 enum Payload {
     Echo { echo: String },
     EchoOk { echo: String },
@@ -16,6 +12,7 @@ enum Payload {
 
 struct Handler;
 
+#[chidori::handler]
 impl chidori::Handler<Payload> for Handler {
     fn handle_message(
         &mut self,
@@ -23,16 +20,10 @@ impl chidori::Handler<Payload> for Handler {
         channel: &mut channel::MessageChannel,
     ) -> Result<(), &'static str> {
         if let Payload::Echo { echo } = &message.body.payload {
-            channel.reply(message, &Payload::EchoOk { echo: echo.clone() })?
+            Payload::respond_echo(channel, message, echo.clone())?
         }
         Ok(())
     }
-
-    fn handle_tick(&mut self, _channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
-        Ok(())
-    }
-
-    fn send_events(&self, _send_channel: &std::sync::mpsc::Sender<chidori::Event>) {}
 }
 
 fn main() -> io::Result<()> {
@@ -1,47 +1,158 @@
-use chidori;
 use chidori::channel;
+use chidori::ids;
+use chidori::kv;
 use chidori::message;
+use chidori::Event;
 use serde::Deserialize;
 use serde::Serialize;
 
+use std::collections::VecDeque;
+use std::env;
 use std::io;
+use std::path;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
 
-#[derive(Serialize, Deserialize)]
+const TICK_INTERVAL_MILLIS: u64 = 100;
+
+const BLOCK_SIZE: u64 = 1000;
+const KV_TIMEOUT_MILLIS: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 enum Payload {
     Generate,
-    GenerateOk { id: String },
+    GenerateOk { id: serde_json::Value },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum Envelope {
+    Ids(Payload),
+    Kv(kv::Payload),
+}
+
+/// Either time-based IDs, or counters reserved ahead in a store when `CHIDORI_ID_STORE` is
+/// `file` or `lin-kv`.
+enum Generator {
+    Clock(ids::Generator),
+    Reserved(ids::Reserver),
 }
 
 struct Handler {
-    counter: usize,
+    store: Option<String>,
+    format: ids::Format,
+    /// Created once the index of this node is known.
+    generator: Option<Generator>,
+    /// Requests waiting for a block to be reserved.
+    waiting: VecDeque<message::Message<Envelope>>,
+}
+
+impl Handler {
+    fn generator(
+        &mut self,
+        channel: &channel::MessageChannel,
+    ) -> Result<&mut Generator, &'static str> {
+        if self.generator.is_none() {
+            let node = channel
+                .node_ids
+                .iter()
+                .position(|n| *n == channel.node_id)
+                .ok_or("unknown node")? as u64;
+            let store = match self.store.as_deref() {
+                None => None,
+                Some("file") => {
+                    let dir = env::var("CHIDORI_ID_DIR").unwrap_or_else(|_| ".".to_string());
+                    let file = path::Path::new(&dir).join(format!("{}.ids", channel.node_id));
+                    Some(ids::Store::File(file))
+                }
+                Some("lin-kv") => Some(ids::Store::Kv {
+                    service: kv::LIN_KV.to_string(),
+                    key: format!("unique-ids/{}", channel.node_id),
+                }),
+                Some(_) => return Err("unknown id store"),
+            };
+            self.generator = Some(match store {
+                Some(store) => Generator::Reserved(ids::Reserver::new(
+                    node,
+                    store,
+                    BLOCK_SIZE,
+                    time::Duration::from_millis(KV_TIMEOUT_MILLIS),
+                )?),
+                None => Generator::Clock(ids::Generator::new(node, self.format)?),
+            });
+        }
+        Ok(self.generator.as_mut().unwrap())
+    }
+
+    /// Replies to waiting requests for as long as there are IDs at hand.
+    fn serve(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        while !self.waiting.is_empty() {
+            let id = match self.generator(channel)? {
+                Generator::Clock(generator) => generator.next_id()?,
+                Generator::Reserved(reserver) => match reserver.next_id(channel)? {
+                    Some(id) => self.format.format(id),
+                    None => return Ok(()),
+                },
+            };
+            let message = self.waiting.pop_front().unwrap();
+            channel.reply(&message, &Payload::GenerateOk { id })?;
+        }
+        Ok(())
+    }
 }
 
-impl chidori::Handler<Payload> for Handler {
+impl chidori::Handler<Envelope> for Handler {
     fn handle_message(
         &mut self,
-        message: &message::Message<Payload>,
+        message: &message::Message<Envelope>,
         channel: &mut channel::MessageChannel,
     ) -> Result<(), &'static str> {
-        let id = format!("{}{}", channel.node_id, self.counter);
-        self.counter += 1;
-
-        if let Payload::Generate = message.body.payload {
-            channel.reply(&message, &Payload::GenerateOk { id })?
+        match &message.body.payload {
+            Envelope::Ids(Payload::Generate) => self.waiting.push_back(message.clone()),
+            Envelope::Kv(payload) => {
+                if let Generator::Reserved(reserver) = self.generator(channel)? {
+                    reserver.handle(message.body.in_reply_to, payload, channel)?;
+                }
+            }
+            _ => {}
         }
-
-        Ok(())
+        self.serve(channel)
     }
 
-    fn handle_tick(&mut self, _channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
-        Ok(())
+    fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if let Generator::Reserved(reserver) = self.generator(channel)? {
+            reserver.tick(channel)?;
+        }
+        self.serve(channel)
     }
 
-    fn send_events(&self, _send_channel: &std::sync::mpsc::Sender<chidori::Event>) {}
+    fn send_events(&self, send_channel: &mpsc::Sender<chidori::Event>) {
+        let send_channel = send_channel.clone();
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_millis(TICK_INTERVAL_MILLIS));
+            send_channel.send(Event::Tick).unwrap();
+        });
+    }
 }
 
 fn main() -> io::Result<()> {
-    let mut handler = Handler { counter: 0 };
+    let format = match env::var("CHIDORI_ID_FORMAT") {
+        Ok(value) => value.parse().expect("invalid CHIDORI_ID_FORMAT"),
+        Err(_) => ids::Format::Number,
+    };
+    let store = env::var("CHIDORI_ID_STORE").ok();
+    // reserved IDs are 64-bit counters, which cannot be shown as UUIDs or ULIDs
+    if store.is_some() && !format.is_64_bit() {
+        panic!("CHIDORI_ID_STORE only supports the number, hex and base32 formats");
+    }
+    let mut handler = Handler {
+        store,
+        format,
+        generator: None,
+        waiting: VecDeque::new(),
+    };
     chidori::main_loop(&mut handler)
 }
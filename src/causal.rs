@@ -0,0 +1,210 @@
+//! Causal broadcast with vector clocks.
+//!
+//! Every broadcast carries the vector clock of its origin at the time it was sent: how many
+//! messages it had delivered from each node, counting itself one ahead. A message is delivered
+//! once every message it depends on has been, and buffered until then, so no node sees an
+//! effect before its cause.
+//!
+//! Broadcasts are sent once to every node. Nodes that missed some catch up by periodically
+//! sending their clock to a random peer, which answers with the messages it has delivered
+//! past it.
+
+use std::collections::BTreeMap;
+use std::time;
+
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+use crate::version_vector::VersionVector;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload<T> {
+    CausalBroadcast {
+        origin: String,
+        clock: VersionVector,
+        value: T,
+    },
+    CausalSync {
+        clock: VersionVector,
+    },
+}
+
+struct Stamped<T> {
+    clock: VersionVector,
+    value: T,
+}
+
+pub struct Causal<T> {
+    sync_interval: time::Duration,
+    last_sync: time::Instant,
+
+    delivered: VersionVector,
+    /// Delivered messages by origin, in sequence order, to answer syncs.
+    log: BTreeMap<String, Vec<Stamped<T>>>,
+    pending: BTreeMap<(String, u64), Stamped<T>>,
+}
+
+impl<T> Causal<T>
+where
+    T: Clone + Serialize,
+{
+    /// Creates a causal broadcast that syncs with a random peer every `sync_interval`.
+    pub fn new(sync_interval: time::Duration) -> Self {
+        Self {
+            sync_interval,
+            last_sync: time::Instant::now(),
+            delivered: VersionVector::new(),
+            log: BTreeMap::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// The number of messages delivered from each origin.
+    pub fn clock(&self) -> &VersionVector {
+        &self.delivered
+    }
+
+    /// The number of messages received but waiting for their dependencies.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Broadcasts a value, delivering it locally right away.
+    pub fn broadcast<F>(
+        &mut self,
+        value: T,
+        channel: &mut channel::MessageChannel,
+        mut deliver: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnMut(&str, &T),
+    {
+        let origin = channel.node_id.clone();
+        *self.delivered.entry(origin.clone()).or_default() += 1;
+        let message = Payload::CausalBroadcast {
+            origin: origin.clone(),
+            clock: self.delivered.clone(),
+            value: value.clone(),
+        };
+        let peers: Vec<String> = channel
+            .node_ids
+            .iter()
+            .filter(|n| **n != origin)
+            .cloned()
+            .collect();
+        for peer in &peers {
+            channel.send(peer, &message)?;
+        }
+
+        deliver(&origin, &value);
+        self.log.entry(origin).or_default().push(Stamped {
+            clock: self.delivered.clone(),
+            value,
+        });
+        Ok(())
+    }
+
+    /// Handles a causal broadcast message, calling `deliver` with the origin and value of
+    /// every message that became deliverable, in causal order.
+    pub fn handle<F>(
+        &mut self,
+        src: &str,
+        payload: &Payload<T>,
+        channel: &mut channel::MessageChannel,
+        deliver: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnMut(&str, &T),
+    {
+        match payload {
+            Payload::CausalBroadcast {
+                origin,
+                clock,
+                value,
+            } => {
+                let seq = clock.get(origin).copied().unwrap_or(0);
+                if seq <= self.delivered.get(origin).copied().unwrap_or(0) {
+                    return Ok(());
+                }
+                self.pending
+                    .entry((origin.clone(), seq))
+                    .or_insert_with(|| Stamped {
+                        clock: clock.clone(),
+                        value: value.clone(),
+                    });
+                self.deliver_ready(deliver);
+            }
+            Payload::CausalSync { clock } => {
+                for (origin, log) in &self.log {
+                    let from = clock.get(origin).copied().unwrap_or(0) as usize;
+                    for stamped in log.iter().skip(from) {
+                        let message = Payload::CausalBroadcast {
+                            origin: origin.clone(),
+                            clock: stamped.clock.clone(),
+                            value: stamped.value.clone(),
+                        };
+                        channel.send(src, &message)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks a random peer for missed messages once per sync interval.
+    pub fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if self.last_sync.elapsed() < self.sync_interval {
+            return Ok(());
+        }
+        self.last_sync = time::Instant::now();
+
+        let peers: Vec<&String> = channel
+            .node_ids
+            .iter()
+            .filter(|n| **n != channel.node_id)
+            .collect();
+        let Some(peer) = peers.choose(&mut rand::thread_rng()).map(|p| p.to_string()) else {
+            return Ok(());
+        };
+        let sync = Payload::<T>::CausalSync {
+            clock: self.delivered.clone(),
+        };
+        channel.send(&peer, &sync)
+    }
+
+    fn is_deliverable(&self, origin: &str, clock: &VersionVector) -> bool {
+        clock.iter().all(|(node, &seen)| {
+            let delivered = self.delivered.get(node).copied().unwrap_or(0);
+            if node == origin {
+                seen == delivered + 1
+            } else {
+                seen <= delivered
+            }
+        })
+    }
+
+    fn deliver_ready<F>(&mut self, mut deliver: F)
+    where
+        F: FnMut(&str, &T),
+    {
+        // delivering a message may unblock others, so scan until nothing changes
+        loop {
+            let ready = self
+                .pending
+                .iter()
+                .find(|((origin, _), stamped)| self.is_deliverable(origin, &stamped.clock))
+                .map(|(key, _)| key.clone());
+            let Some((origin, seq)) = ready else {
+                return;
+            };
+            let stamped = self.pending.remove(&(origin.clone(), seq)).unwrap();
+            self.delivered.insert(origin.clone(), seq);
+            deliver(&origin, &stamped.value);
+            self.log.entry(origin).or_default().push(stamped);
+        }
+    }
+}
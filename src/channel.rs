@@ -1,23 +1,45 @@
-use std::io;
-
 use serde::Serialize;
 
+use crate::clock;
 use crate::init::Init;
 use crate::message;
 
+#[cfg(not(test))]
 fn write<T>(message: &message::Message<T>)
 where
     T: Serialize,
 {
-    serde_json::to_writer(io::stdout(), &message).unwrap();
+    serde_json::to_writer(std::io::stdout(), &message).unwrap();
     println!();
 }
 
+#[cfg(test)]
+thread_local! {
+    static WRITTEN: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Tests collect what the channels on their thread write, instead of printing it.
+#[cfg(test)]
+fn write<T>(message: &message::Message<T>)
+where
+    T: Serialize,
+{
+    let line = serde_json::to_string(message).unwrap();
+    WRITTEN.with(|written| written.borrow_mut().push(line));
+}
+
+/// The messages written on this thread since the last call.
+#[cfg(test)]
+pub(crate) fn take_written() -> Vec<String> {
+    WRITTEN.with(|written| written.take())
+}
+
 pub struct MessageChannel {
     pub node_id: String,
     pub node_ids: Vec<String>,
 
     counter: usize,
+    clock: Option<Box<dyn clock::AnyClock>>,
 }
 
 impl From<&Init> for MessageChannel {
@@ -26,12 +48,28 @@ impl From<&Init> for MessageChannel {
             node_id: value.node_id.clone(),
             node_ids: value.node_ids.clone(),
             counter: 0,
+            clock: None,
         }
     }
 }
 
 impl MessageChannel {
     pub fn send<T>(&mut self, node: &str, payload: &T) -> Result<(), &'static str>
+    where
+        T: Serialize,
+    {
+        let msg_id = self.next_msg_id();
+        self.send_with_id(node, msg_id, payload)
+    }
+
+    /// Sends `payload` under a message id taken earlier from `next_msg_id`, so that
+    /// retransmissions of a message can be recognized as such by the receiver.
+    pub fn send_with_id<T>(
+        &mut self,
+        node: &str,
+        msg_id: usize,
+        payload: &T,
+    ) -> Result<(), &'static str>
     where
         T: Serialize,
     {
@@ -39,8 +77,9 @@ impl MessageChannel {
             src: self.node_id.clone(),
             dest: node.to_string(),
             body: message::MessageBody {
-                msg_id: Some(self.get_counter()),
+                msg_id: Some(msg_id),
                 in_reply_to: None,
+                stamp: self.stamp(node)?,
                 payload,
             },
         };
@@ -49,20 +88,21 @@ impl MessageChannel {
         Ok(())
     }
 
-    pub fn reply<T>(
+    pub fn reply<T, U>(
         &mut self,
         received: &message::Message<T>,
-        payload: &T,
+        payload: &U,
     ) -> Result<(), &'static str>
     where
-        T: Serialize,
+        U: Serialize,
     {
         let reply_message = message::Message {
             src: self.node_id.clone(),
             dest: received.src.clone(),
             body: message::MessageBody {
-                msg_id: Some(self.get_counter()),
+                msg_id: Some(self.next_msg_id()),
                 in_reply_to: received.body.msg_id,
+                stamp: self.stamp(&received.src)?,
                 payload,
             },
         };
@@ -71,9 +111,52 @@ impl MessageChannel {
         Ok(())
     }
 
-    fn get_counter(&mut self) -> usize {
+    pub fn next_msg_id(&mut self) -> usize {
         let value = self.counter;
         self.counter += 1;
         value
     }
+
+    /// Starts stamping messages to other nodes with `clock`, and advancing it past the stamps
+    /// of received messages.
+    pub fn set_clock<C>(&mut self, clock: C)
+    where
+        C: clock::Clock + 'static,
+    {
+        self.clock = Some(Box::new(clock));
+    }
+
+    /// The clock passed to `set_clock`, if it is a `C`.
+    pub fn clock<C>(&self) -> Option<&C>
+    where
+        C: clock::Clock + 'static,
+    {
+        self.clock.as_ref()?.as_any().downcast_ref()
+    }
+
+    pub fn clock_mut<C>(&mut self) -> Option<&mut C>
+    where
+        C: clock::Clock + 'static,
+    {
+        self.clock.as_mut()?.as_any_mut().downcast_mut()
+    }
+
+    /// Advances the clock past the stamp of a received message.
+    pub fn observe<T>(&mut self, received: &message::Message<T>) -> Result<(), &'static str> {
+        match (&mut self.clock, &received.body.stamp) {
+            (Some(clock), Some(stamp)) => clock.observe_stamp(&self.node_id, stamp),
+            _ => Ok(()),
+        }
+    }
+
+    fn stamp(&mut self, node: &str) -> Result<Option<serde_json::Value>, &'static str> {
+        // clients and services would not expect the extra field
+        if !self.node_ids.iter().any(|n| n == node) {
+            return Ok(None);
+        }
+        match &mut self.clock {
+            Some(clock) => clock.stamp(&self.node_id).map(Some),
+            None => Ok(None),
+        }
+    }
 }
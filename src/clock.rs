@@ -0,0 +1,244 @@
+//! Logical clocks.
+//!
+//! - [`Lamport`] clocks order events consistently with causality, but cannot tell concurrent
+//!   events apart.
+//! - [`VectorClock`]s capture causality exactly, at the cost of an entry per node.
+//! - [`HybridClock`]s stay close to wall-clock time while never going backwards, and break
+//!   ties between events within the same millisecond with a logical counter.
+//!
+//! Any [`Clock`] can be handed to `main_loop_with_clock`, which stamps every message sent to
+//! another node and advances the clock past the stamp of every message received.
+
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::time;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+pub trait Clock {
+    type Timestamp: Serialize + DeserializeOwned;
+
+    /// Advances the clock for a local event or a message about to be sent.
+    fn tick(&mut self, node_id: &str) -> Self::Timestamp;
+
+    /// Advances the clock past a timestamp received from another node.
+    fn observe(&mut self, node_id: &str, timestamp: &Self::Timestamp) -> Self::Timestamp;
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct Lamport {
+    time: u64,
+}
+
+impl Lamport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+}
+
+impl Clock for Lamport {
+    type Timestamp = u64;
+
+    fn tick(&mut self, _node_id: &str) -> u64 {
+        self.time += 1;
+        self.time
+    }
+
+    fn observe(&mut self, _node_id: &str, timestamp: &u64) -> u64 {
+        self.time = self.time.max(*timestamp) + 1;
+        self.time
+    }
+}
+
+/// The number of events seen from each node. Vector clocks are only partially ordered:
+/// `partial_cmp` returns `None` for concurrent clocks.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct VectorClock {
+    entries: BTreeMap<String, u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node_id: &str) -> u64 {
+        self.entries.get(node_id).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, node_id: &str) -> u64 {
+        let entry = self.entries.entry(node_id.to_string()).or_default();
+        *entry += 1;
+        *entry
+    }
+
+    /// Takes the entry-wise maximum with `other`.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, &count) in &other.entries {
+            let entry = self.entries.entry(node.clone()).or_default();
+            *entry = (*entry).max(count);
+        }
+    }
+
+    /// Whether every event seen by `other` was seen by this clock as well.
+    pub fn dominates(&self, other: &VectorClock) -> bool {
+        other
+            .entries
+            .iter()
+            .all(|(node, &count)| self.get(node) >= count)
+    }
+
+    pub fn is_concurrent(&self, other: &VectorClock) -> bool {
+        !self.dominates(other) && !other.dominates(self)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.entries
+            .iter()
+            .map(|(node, &count)| (node.as_str(), count))
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self.dominates(other), other.dominates(self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (false, false) => None,
+        }
+    }
+}
+
+impl Clock for VectorClock {
+    type Timestamp = VectorClock;
+
+    fn tick(&mut self, node_id: &str) -> VectorClock {
+        self.increment(node_id);
+        self.clone()
+    }
+
+    fn observe(&mut self, node_id: &str, timestamp: &VectorClock) -> VectorClock {
+        self.merge(timestamp);
+        self.increment(node_id);
+        self.clone()
+    }
+}
+
+/// A hybrid logical timestamp: milliseconds since the epoch, and a counter for events that
+/// happened within the same millisecond as far as the clock can tell.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub wall: u64,
+    pub logical: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct HybridClock {
+    last: Timestamp,
+}
+
+fn wall_clock() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl HybridClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The latest timestamp handed out.
+    pub fn last(&self) -> Timestamp {
+        self.last
+    }
+}
+
+impl Clock for HybridClock {
+    type Timestamp = Timestamp;
+
+    fn tick(&mut self, _node_id: &str) -> Timestamp {
+        let now = wall_clock();
+        self.last = if now > self.last.wall {
+            Timestamp {
+                wall: now,
+                logical: 0,
+            }
+        } else {
+            Timestamp {
+                wall: self.last.wall,
+                logical: self.last.logical + 1,
+            }
+        };
+        self.last
+    }
+
+    fn observe(&mut self, _node_id: &str, timestamp: &Timestamp) -> Timestamp {
+        let wall = wall_clock().max(self.last.wall).max(timestamp.wall);
+        let logical = if wall == self.last.wall && wall == timestamp.wall {
+            self.last.logical.max(timestamp.logical) + 1
+        } else if wall == self.last.wall {
+            self.last.logical + 1
+        } else if wall == timestamp.wall {
+            timestamp.logical + 1
+        } else {
+            0
+        };
+        self.last = Timestamp { wall, logical };
+        self.last
+    }
+}
+
+/// A [`Clock`] with its timestamps in JSON, so that a channel can hold any kind of clock.
+pub trait AnyClock {
+    fn stamp(&mut self, node_id: &str) -> Result<serde_json::Value, &'static str>;
+
+    fn observe_stamp(
+        &mut self,
+        node_id: &str,
+        stamp: &serde_json::Value,
+    ) -> Result<(), &'static str>;
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<C> AnyClock for C
+where
+    C: Clock + 'static,
+{
+    fn stamp(&mut self, node_id: &str) -> Result<serde_json::Value, &'static str> {
+        serde_json::to_value(self.tick(node_id)).map_err(|_| "unserializable timestamp")
+    }
+
+    fn observe_stamp(
+        &mut self,
+        node_id: &str,
+        stamp: &serde_json::Value,
+    ) -> Result<(), &'static str> {
+        let timestamp = C::Timestamp::deserialize(stamp).map_err(|_| "invalid timestamp")?;
+        self.observe(node_id, &timestamp);
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
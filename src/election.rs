@@ -0,0 +1,291 @@
+//! Raft-style leader election without a log.
+//!
+//! Nodes time out, become candidates for a new term and win with a majority of votes. The
+//! leader heartbeats every node; each round acknowledged by a majority grants it a lease, during
+//! which no other node can be elected. While the lease holds, the leader may serve reads from
+//! local state without asking anyone.
+//!
+//! Leases rely on clocks advancing at roughly the same rate on every node, which is why they
+//! are shorter than the election timeout.
+
+use std::collections::HashSet;
+use std::time;
+
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    RequestVote { term: u64 },
+    Vote { term: u64, granted: bool },
+    LeaderHeartbeat { term: u64, round: u64 },
+    LeaderHeartbeatAck { term: u64, round: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct Round {
+    number: u64,
+    started: time::Instant,
+    acks: HashSet<String>,
+}
+
+pub struct Election {
+    election_timeout: time::Duration,
+    heartbeat_interval: time::Duration,
+
+    term: u64,
+    role: Role,
+    voted_for: Option<String>,
+    leader: Option<String>,
+
+    last_heard: time::Instant,
+    election_deadline: time::Instant,
+    votes: HashSet<String>,
+
+    round: Option<Round>,
+    next_round: u64,
+    lease_expiry: Option<time::Instant>,
+}
+
+impl Election {
+    /// Creates an election where followers wait between one and two `election_timeout`s
+    /// without hearing from a leader before running, and leaders heartbeat every
+    /// `heartbeat_interval`, which should be a fraction of the timeout.
+    pub fn new(election_timeout: time::Duration, heartbeat_interval: time::Duration) -> Self {
+        let now = time::Instant::now();
+        let mut election = Self {
+            election_timeout,
+            heartbeat_interval,
+            term: 0,
+            role: Role::Follower,
+            voted_for: None,
+            leader: None,
+            last_heard: now,
+            election_deadline: now,
+            votes: HashSet::new(),
+            round: None,
+            next_round: 0,
+            lease_expiry: None,
+        };
+        election.reset_deadline();
+        election
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    /// The leader of the current term, as far as this node knows.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    /// Whether this node is the leader and holds a lease, so that no other leader can exist.
+    pub fn has_lease(&self) -> bool {
+        self.is_leader()
+            && self
+                .lease_expiry
+                .is_some_and(|expiry| time::Instant::now() < expiry)
+    }
+
+    pub fn handle(
+        &mut self,
+        src: &str,
+        payload: &Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        match payload {
+            Payload::RequestVote { term } => {
+                // a node that still hears from a leader does not help depose it, which is what
+                // makes leases safe
+                let leader_alive = self.has_lease()
+                    || (!self.is_leader()
+                        && self.leader.is_some()
+                        && self.leader.as_deref() != Some(src)
+                        && self.last_heard.elapsed() < self.election_timeout);
+                if *term > self.term && !leader_alive {
+                    self.step_down(*term, None);
+                }
+                let granted = *term == self.term
+                    && !leader_alive
+                    && self.voted_for.as_deref().is_none_or(|voted| voted == src);
+                if granted {
+                    self.voted_for = Some(src.to_string());
+                    self.reset_deadline();
+                }
+                let vote = Payload::Vote {
+                    term: self.term,
+                    granted,
+                };
+                channel.send(src, &vote)?;
+            }
+            Payload::Vote { term, granted } => {
+                if *term > self.term {
+                    self.step_down(*term, None);
+                } else if *term == self.term && *granted && self.role == Role::Candidate {
+                    self.votes.insert(src.to_string());
+                    if self.votes.len() >= majority(channel) {
+                        self.become_leader(channel)?;
+                    }
+                }
+            }
+            Payload::LeaderHeartbeat { term, round } => {
+                if *term >= self.term {
+                    if *term > self.term || self.role != Role::Follower {
+                        self.step_down(*term, Some(src));
+                    }
+                    self.leader = Some(src.to_string());
+                    self.last_heard = time::Instant::now();
+                    self.reset_deadline();
+                }
+                let ack = Payload::LeaderHeartbeatAck {
+                    term: self.term,
+                    round: *round,
+                };
+                channel.send(src, &ack)?;
+            }
+            Payload::LeaderHeartbeatAck { term, round } => {
+                if *term > self.term {
+                    self.step_down(*term, None);
+                    return Ok(());
+                }
+                if *term < self.term || self.role != Role::Leader {
+                    return Ok(());
+                }
+                let majority = majority(channel);
+                let Some(current) = self.round.as_mut() else {
+                    return Ok(());
+                };
+                if current.number == *round {
+                    current.acks.insert(src.to_string());
+                    if current.acks.len() >= majority {
+                        self.lease_expiry = Some(current.started + self.lease_duration());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts an election when the leader has been silent for too long, and heartbeats
+    /// followers while leading.
+    pub fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        match self.role {
+            Role::Leader => {
+                let due = self
+                    .round
+                    .as_ref()
+                    .is_none_or(|round| round.started.elapsed() >= self.heartbeat_interval);
+                if due {
+                    self.start_round(channel)?;
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if time::Instant::now() >= self.election_deadline {
+                    self.start_election(channel)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lease_duration(&self) -> time::Duration {
+        // leave room for clock drift between the leader and its followers
+        self.election_timeout / 2
+    }
+
+    fn reset_deadline(&mut self) {
+        let jitter = rand::thread_rng().gen_range(0.0..1.0);
+        self.election_deadline = time::Instant::now() + self.election_timeout.mul_f64(1.0 + jitter);
+    }
+
+    fn step_down(&mut self, term: u64, leader: Option<&str>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader.map(str::to_string);
+        self.votes.clear();
+        self.round = None;
+        self.lease_expiry = None;
+    }
+
+    fn start_election(
+        &mut self,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(channel.node_id.clone());
+        self.votes = HashSet::from([channel.node_id.clone()]);
+        self.reset_deadline();
+
+        if self.votes.len() >= majority(channel) {
+            return self.become_leader(channel);
+        }
+        let request = Payload::RequestVote { term: self.term };
+        for node in channel.node_ids.clone() {
+            if node != channel.node_id {
+                channel.send(&node, &request)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        self.role = Role::Leader;
+        self.leader = Some(channel.node_id.clone());
+        self.votes.clear();
+        self.start_round(channel)
+    }
+
+    fn start_round(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        let number = self.next_round;
+        self.next_round += 1;
+        let round = Round {
+            number,
+            started: time::Instant::now(),
+            acks: HashSet::from([channel.node_id.clone()]),
+        };
+        if round.acks.len() >= majority(channel) {
+            self.lease_expiry = Some(round.started + self.lease_duration());
+        }
+        self.round = Some(round);
+
+        let heartbeat = Payload::LeaderHeartbeat {
+            term: self.term,
+            round: number,
+        };
+        for node in channel.node_ids.clone() {
+            if node != channel.node_id {
+                channel.send(&node, &heartbeat)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn majority(channel: &channel::MessageChannel) -> usize {
+    channel.node_ids.len() / 2 + 1
+}
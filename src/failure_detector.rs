@@ -0,0 +1,167 @@
+//! Phi-accrual failure detection.
+//!
+//! Instead of a binary alive/dead verdict, the detector keeps a window of heartbeat
+//! inter-arrival times per peer and computes `phi`, the suspicion that a peer has failed given
+//! how long it has been silent. A peer is suspected once `phi` crosses a threshold.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::time;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+
+const WINDOW_SIZE: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    Heartbeat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Suspect(String),
+    Recover(String),
+}
+
+struct History {
+    last_arrival: time::Instant,
+    /// Inter-arrival times in milliseconds.
+    intervals: VecDeque<f64>,
+}
+
+impl History {
+    fn new(heartbeat_interval: time::Duration) -> Self {
+        // bootstrap with the expected interval until real heartbeats arrive
+        Self {
+            last_arrival: time::Instant::now(),
+            intervals: VecDeque::from([heartbeat_interval.as_secs_f64() * 1000.0]),
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        self.intervals.iter().sum::<f64>() / self.intervals.len() as f64
+    }
+
+    fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        let variance = self
+            .intervals
+            .iter()
+            .map(|i| (i - mean) * (i - mean))
+            .sum::<f64>()
+            / self.intervals.len() as f64;
+        variance.sqrt()
+    }
+}
+
+pub struct FailureDetector {
+    heartbeat_interval: time::Duration,
+    threshold: f64,
+
+    last_sent: Option<time::Instant>,
+    histories: HashMap<String, History>,
+    suspected: HashSet<String>,
+}
+
+impl FailureDetector {
+    /// Creates a detector that heartbeats every `heartbeat_interval` and suspects peers whose
+    /// phi exceeds `threshold`. A threshold of 8 is a common starting point.
+    pub fn new(heartbeat_interval: time::Duration, threshold: f64) -> Self {
+        Self {
+            heartbeat_interval,
+            threshold,
+            last_sent: None,
+            histories: HashMap::new(),
+            suspected: HashSet::new(),
+        }
+    }
+
+    /// Records that a message arrived from `peer`. Any message counts as a heartbeat.
+    pub fn heartbeat(&mut self, peer: &str) {
+        let now = time::Instant::now();
+        match self.histories.get_mut(peer) {
+            Some(history) => {
+                let interval = now.duration_since(history.last_arrival);
+                history.last_arrival = now;
+                if history.intervals.len() == WINDOW_SIZE {
+                    history.intervals.pop_front();
+                }
+                history.intervals.push_back(interval.as_secs_f64() * 1000.0);
+            }
+            None => {
+                self.histories
+                    .insert(peer.to_string(), History::new(self.heartbeat_interval));
+            }
+        }
+    }
+
+    /// The suspicion level for `peer`: a phi of 1 means a 10% chance of being wrong when
+    /// suspecting it, a phi of 2 means 1%, and so on.
+    pub fn phi(&self, peer: &str) -> f64 {
+        let Some(history) = self.histories.get(peer) else {
+            return 0.0;
+        };
+
+        let elapsed = history.last_arrival.elapsed().as_secs_f64() * 1000.0;
+        let mean = history.mean();
+        // avoid an infinitely confident detector when heartbeats are perfectly regular
+        let std_dev = history.std_dev().max(mean / 4.0);
+
+        // logistic approximation of the normal cumulative distribution function
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+
+    pub fn is_suspected(&self, peer: &str) -> bool {
+        self.suspected.contains(peer)
+    }
+
+    /// Sends heartbeats when they are due and re-evaluates every peer, returning the peers
+    /// that started or stopped being suspected since the last tick.
+    pub fn tick(
+        &mut self,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<Vec<Event>, &'static str> {
+        let due = self
+            .last_sent
+            .is_none_or(|sent| sent.elapsed() >= self.heartbeat_interval);
+        if due {
+            self.last_sent = Some(time::Instant::now());
+            for node in channel.node_ids.clone() {
+                if node != channel.node_id {
+                    channel.send(&node, &Payload::Heartbeat)?;
+                }
+            }
+        }
+
+        let mut events = Vec::new();
+        for node in &channel.node_ids {
+            if *node == channel.node_id {
+                continue;
+            }
+            if !self.histories.contains_key(node) {
+                // start the clock for peers we never heard from
+                self.histories
+                    .insert(node.clone(), History::new(self.heartbeat_interval));
+            }
+            let suspect = self.phi(node) > self.threshold;
+            if suspect && self.suspected.insert(node.clone()) {
+                events.push(Event::Suspect(node.clone()));
+            } else if !suspect && self.suspected.remove(node) {
+                events.push(Event::Recover(node.clone()));
+            }
+        }
+        Ok(events)
+    }
+}
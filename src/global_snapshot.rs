@@ -0,0 +1,677 @@
+//! Consistent global snapshots, after Chandy and Lamport, without assuming messages between
+//! two nodes arrive in order (the variant of Lai and Yang).
+//!
+//! Snapshots are numbered by epoch. Any node can start the next one: it records its own state
+//! and sends a marker to every other node, and so does every node on its first marker of the
+//! epoch. Every application message carries the epoch of its sender, and a message from a later
+//! epoch makes the receiver record its state before taking the message in, so no state counts
+//! a message whose sending is not counted.
+//!
+//! The messages in flight for an epoch are those sent before the sender recorded it and
+//! received after the receiver did. Each marker carries how many messages its sender sent on
+//! that channel before recording, and the receiver records messages from the earlier epoch
+//! until it has received that many. Once every channel is complete, it reports its state and
+//! the recorded messages to the initiator, which puts the reports together into a [`Cut`].
+//! Nodes that start the same epoch concurrently get the same cut.
+//!
+//! Markers and reports are resent on tick until they are acknowledged. A lost application
+//! message leaves its channel incomplete though, so an epoch that is not complete within the
+//! timeout is given up on: every node drops its recording, and the initiator fails the snapshot.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::time;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SnapshotId {
+    pub initiator: String,
+    pub epoch: u64,
+}
+
+/// A message that was sent before `src` recorded its state, and received after `dest` did.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InFlight<M> {
+    pub src: String,
+    pub dest: String,
+    pub message: M,
+}
+
+/// The state of every node, and the messages in flight between them, at one consistent point.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Cut<S, M> {
+    pub id: SnapshotId,
+    pub states: BTreeMap<String, S>,
+    pub in_flight: Vec<InFlight<M>>,
+}
+
+/// A snapshot started here, and its cut unless it timed out.
+pub type Outcome<S, M> = (SnapshotId, Result<Cut<S, M>, &'static str>);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload<S, M> {
+    SnapshotMarker {
+        epoch: u64,
+        /// The node that started the epoch, if the sender knows it yet.
+        initiator: Option<String>,
+        /// The number of messages the sender sent to the receiver before recording.
+        sent: u64,
+    },
+    SnapshotMarkerOk {
+        epoch: u64,
+    },
+    SnapshotReport {
+        id: SnapshotId,
+        state: S,
+        in_flight: Vec<InFlight<M>>,
+    },
+    SnapshotReportOk {
+        id: SnapshotId,
+    },
+}
+
+impl<S, M> Payload<S, M> {
+    /// Whether `ack`, from the node this was sent to, acknowledges it.
+    fn acknowledged_by(&self, ack: &Payload<S, M>) -> bool {
+        match (self, ack) {
+            (Payload::SnapshotMarker { epoch, .. }, Payload::SnapshotMarkerOk { epoch: acked }) => {
+                epoch == acked
+            }
+            (Payload::SnapshotReport { id, .. }, Payload::SnapshotReportOk { id: acked }) => {
+                id == acked
+            }
+            _ => false,
+        }
+    }
+}
+
+struct Recording<S, M> {
+    state: S,
+    initiators: BTreeSet<String>,
+    /// Messages each node sent here before recording, once its marker arrived.
+    expected: HashMap<String, u64>,
+    /// Messages received from each node that it sent before recording.
+    received: HashMap<String, u64>,
+    in_flight: Vec<InFlight<M>>,
+    started: time::Instant,
+}
+
+/// A recording that completed, kept for initiators whose marker arrives after that.
+struct Finished<S, M> {
+    epoch: u64,
+    initiators: BTreeSet<String>,
+    state: S,
+    in_flight: Vec<InFlight<M>>,
+}
+
+/// A marker or report that was not acknowledged yet.
+struct Unacked<S, M> {
+    dest: String,
+    payload: Payload<S, M>,
+    first_sent: time::Instant,
+    last_sent: time::Instant,
+}
+
+/// A snapshot started here, with the reports received so far.
+struct Collecting<S, M> {
+    cut: Cut<S, M>,
+    started: time::Instant,
+}
+
+pub struct Snapshots<S, M> {
+    resend_interval: time::Duration,
+    timeout: time::Duration,
+
+    /// The latest epoch recorded here.
+    epoch: u64,
+    /// Application messages sent to and received from each node.
+    sent: HashMap<String, u64>,
+    received: HashMap<String, u64>,
+
+    recording: BTreeMap<u64, Recording<S, M>>,
+    finished: Option<Finished<S, M>>,
+    collecting: BTreeMap<SnapshotId, Collecting<S, M>>,
+    completed: Vec<Outcome<S, M>>,
+
+    unacked: Vec<Unacked<S, M>>,
+}
+
+impl<S, M> Snapshots<S, M>
+where
+    S: Clone + Serialize,
+    M: Clone + Serialize,
+{
+    /// Creates a snapshot facility that resends markers and reports every `resend_interval`,
+    /// and gives up on epochs that are not complete after `timeout`.
+    pub fn new(resend_interval: time::Duration, timeout: time::Duration) -> Self {
+        Self {
+            resend_interval,
+            timeout,
+            epoch: 0,
+            sent: HashMap::new(),
+            received: HashMap::new(),
+            recording: BTreeMap::new(),
+            finished: None,
+            collecting: BTreeMap::new(),
+            completed: Vec::new(),
+            unacked: Vec::new(),
+        }
+    }
+
+    /// Starts a snapshot, with `state` as the state of this node.
+    pub fn start(
+        &mut self,
+        state: S,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<SnapshotId, &'static str> {
+        let id = SnapshotId {
+            initiator: channel.node_id.clone(),
+            epoch: self.epoch + 1,
+        };
+        self.collecting.insert(
+            id.clone(),
+            Collecting {
+                cut: Cut {
+                    id: id.clone(),
+                    states: BTreeMap::new(),
+                    in_flight: Vec::new(),
+                },
+                started: time::Instant::now(),
+            },
+        );
+        self.record(id.epoch, state, Some(&id.initiator), channel)?;
+        self.report_if_complete(id.epoch, channel)?;
+        Ok(id)
+    }
+
+    /// Counts an application message about to be sent to `dest`, returning the epoch to send
+    /// along with it.
+    pub fn send_epoch(&mut self, dest: &str) -> u64 {
+        *self.sent.entry(dest.to_string()).or_default() += 1;
+        self.epoch
+    }
+
+    /// Takes in an application message from another node, sent in `epoch`, before its effect
+    /// is part of the state. Calls `state` for the state of this node if the message comes
+    /// from an epoch not recorded here yet.
+    pub fn observe<F>(
+        &mut self,
+        src: &str,
+        epoch: u64,
+        message: &M,
+        channel: &mut channel::MessageChannel,
+        state: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnOnce() -> S,
+    {
+        if epoch > self.epoch {
+            self.record(epoch, state(), None, channel)?;
+        }
+        *self.received.entry(src.to_string()).or_default() += 1;
+
+        let mut complete = Vec::new();
+        for (&recorded, recording) in self.recording.range_mut(epoch + 1..) {
+            *recording.received.entry(src.to_string()).or_default() += 1;
+            recording.in_flight.push(InFlight {
+                src: src.to_string(),
+                dest: channel.node_id.clone(),
+                message: message.clone(),
+            });
+            complete.push(recorded);
+        }
+        for epoch in complete {
+            self.report_if_complete(epoch, channel)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a snapshot message, calling `state` for the state of this node if it is the
+    /// first marker of an epoch.
+    pub fn handle<F>(
+        &mut self,
+        src: &str,
+        payload: &Payload<S, M>,
+        channel: &mut channel::MessageChannel,
+        state: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnOnce() -> S,
+    {
+        match payload {
+            Payload::SnapshotMarker {
+                epoch,
+                initiator,
+                sent,
+            } => {
+                channel.send(src, &Payload::<S, M>::SnapshotMarkerOk { epoch: *epoch })?;
+                if *epoch > self.epoch {
+                    self.record(*epoch, state(), None, channel)?;
+                }
+                if let Some(recording) = self.recording.get_mut(epoch) {
+                    recording.expected.insert(src.to_string(), *sent);
+                }
+                match initiator {
+                    Some(initiator) => self.learn_initiator(*epoch, initiator, channel)?,
+                    None => self.report_if_complete(*epoch, channel)?,
+                }
+            }
+            Payload::SnapshotReport {
+                id,
+                state,
+                in_flight,
+            } => {
+                channel.send(src, &Payload::<S, M>::SnapshotReportOk { id: id.clone() })?;
+                self.collect(id, src, state.clone(), in_flight.clone(), channel);
+            }
+            ack => self
+                .unacked
+                .retain(|unacked| unacked.dest != src || !unacked.payload.acknowledged_by(ack)),
+        }
+        Ok(())
+    }
+
+    /// Resends what was not acknowledged in time, and gives up on epochs that timed out.
+    pub fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        let timeout = self.timeout;
+        self.unacked
+            .retain(|unacked| unacked.first_sent.elapsed() < timeout);
+        for unacked in &mut self.unacked {
+            if unacked.last_sent.elapsed() >= self.resend_interval {
+                channel.send(&unacked.dest, &unacked.payload)?;
+                unacked.last_sent = time::Instant::now();
+            }
+        }
+
+        self.recording
+            .retain(|_, recording| recording.started.elapsed() < timeout);
+        let expired: Vec<SnapshotId> = self
+            .collecting
+            .iter()
+            .filter(|(_, collecting)| collecting.started.elapsed() >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            self.collecting.remove(&id);
+            self.completed.push((id, Err("snapshot timed out")));
+        }
+        Ok(())
+    }
+
+    /// Takes the snapshots started here that every node reported on, or that timed out.
+    pub fn take_completed(&mut self) -> Vec<Outcome<S, M>> {
+        std::mem::take(&mut self.completed)
+    }
+
+    /// Records `state` for every epoch up to `epoch`, and sends their markers. Only the
+    /// initiator of `epoch` knows itself, the other nodes learn it from its markers.
+    fn record(
+        &mut self,
+        epoch: u64,
+        state: S,
+        initiator: Option<&str>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let peers: Vec<String> = channel
+            .node_ids
+            .iter()
+            .filter(|n| **n != channel.node_id)
+            .cloned()
+            .collect();
+        for recorded in self.epoch + 1..=epoch {
+            let initiator = initiator.filter(|_| recorded == epoch);
+            self.recording.insert(
+                recorded,
+                Recording {
+                    state: state.clone(),
+                    initiators: initiator.map(str::to_string).into_iter().collect(),
+                    expected: HashMap::new(),
+                    // everything received so far was sent before its sender recorded
+                    received: self.received.clone(),
+                    in_flight: Vec::new(),
+                    started: time::Instant::now(),
+                },
+            );
+            for peer in &peers {
+                let marker = Payload::SnapshotMarker {
+                    epoch: recorded,
+                    initiator: initiator.map(str::to_string),
+                    sent: self.sent.get(peer).copied().unwrap_or(0),
+                };
+                self.send(peer, marker, channel)?;
+            }
+        }
+        self.epoch = self.epoch.max(epoch);
+        Ok(())
+    }
+
+    /// Reports `epoch` to `initiator` as well, once it is complete or right away if it is.
+    fn learn_initiator(
+        &mut self,
+        epoch: u64,
+        initiator: &str,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        if let Some(recording) = self.recording.get_mut(&epoch) {
+            recording.initiators.insert(initiator.to_string());
+            return self.report_if_complete(epoch, channel);
+        }
+
+        let Some(finished) = &mut self.finished else {
+            return Ok(());
+        };
+        if finished.epoch != epoch || !finished.initiators.insert(initiator.to_string()) {
+            return Ok(());
+        }
+        let (state, in_flight) = (finished.state.clone(), finished.in_flight.clone());
+        self.report(epoch, initiator, state, in_flight, channel)
+    }
+
+    fn report_if_complete(
+        &mut self,
+        epoch: u64,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let Some(recording) = self.recording.get(&epoch) else {
+            return Ok(());
+        };
+        let complete = !recording.initiators.is_empty()
+            && channel
+                .node_ids
+                .iter()
+                .filter(|n| **n != channel.node_id)
+                .all(|peer| {
+                    recording.expected.get(peer).is_some_and(|expected| {
+                        recording.received.get(peer).copied().unwrap_or(0) >= *expected
+                    })
+                });
+        if !complete {
+            return Ok(());
+        }
+
+        let recording = self.recording.remove(&epoch).unwrap();
+        for initiator in &recording.initiators {
+            self.report(
+                epoch,
+                initiator,
+                recording.state.clone(),
+                recording.in_flight.clone(),
+                channel,
+            )?;
+        }
+        if self.finished.as_ref().is_none_or(|f| f.epoch < epoch) {
+            self.finished = Some(Finished {
+                epoch,
+                initiators: recording.initiators,
+                state: recording.state,
+                in_flight: recording.in_flight,
+            });
+        }
+        Ok(())
+    }
+
+    fn report(
+        &mut self,
+        epoch: u64,
+        initiator: &str,
+        state: S,
+        in_flight: Vec<InFlight<M>>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let id = SnapshotId {
+            initiator: initiator.to_string(),
+            epoch,
+        };
+        if initiator == channel.node_id {
+            let node_id = channel.node_id.clone();
+            self.collect(&id, &node_id, state, in_flight, channel);
+            return Ok(());
+        }
+        let report = Payload::SnapshotReport {
+            id,
+            state,
+            in_flight,
+        };
+        self.send(initiator, report, channel)
+    }
+
+    /// Sends `payload`, resending it on tick until `dest` acknowledges it.
+    fn send(
+        &mut self,
+        dest: &str,
+        payload: Payload<S, M>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        channel.send(dest, &payload)?;
+        let now = time::Instant::now();
+        self.unacked.push(Unacked {
+            dest: dest.to_string(),
+            payload,
+            first_sent: now,
+            last_sent: now,
+        });
+        Ok(())
+    }
+
+    fn collect(
+        &mut self,
+        id: &SnapshotId,
+        src: &str,
+        state: S,
+        in_flight: Vec<InFlight<M>>,
+        channel: &channel::MessageChannel,
+    ) {
+        let Some(collecting) = self.collecting.get_mut(id) else {
+            return;
+        };
+        let cut = &mut collecting.cut;
+        if cut.states.insert(src.to_string(), state).is_none() {
+            cut.in_flight.extend(in_flight);
+        }
+        if cut.states.len() == channel.node_ids.len() {
+            let collecting = self.collecting.remove(id).unwrap();
+            self.completed.push((id.clone(), Ok(collecting.cut)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::init::Init;
+    use crate::message;
+
+    type Message = message::Message<Payload<u64, u64>>;
+
+    /// Nodes whose state is the sum of the application messages they received.
+    struct Network {
+        nodes: BTreeMap<String, (Snapshots<u64, u64>, channel::MessageChannel, u64)>,
+        in_transit: VecDeque<Message>,
+    }
+
+    impl Network {
+        fn new(nodes: &[&str], timeout: time::Duration) -> Self {
+            let node_ids: Vec<String> = nodes.iter().map(|n| n.to_string()).collect();
+            let nodes = node_ids
+                .iter()
+                .map(|node_id| {
+                    let init = Init {
+                        node_id: node_id.clone(),
+                        node_ids: node_ids.clone(),
+                    };
+                    let snapshots = Snapshots::new(time::Duration::ZERO, timeout);
+                    let node = (snapshots, channel::MessageChannel::from(&init), 0);
+                    (node_id.clone(), node)
+                })
+                .collect();
+            Self {
+                nodes,
+                in_transit: VecDeque::new(),
+            }
+        }
+
+        fn collect_written(&mut self) {
+            for line in channel::take_written() {
+                self.in_transit
+                    .push_back(serde_json::from_str(&line).unwrap());
+            }
+        }
+
+        fn start(&mut self, node: &str) -> SnapshotId {
+            let (snapshots, channel, state) = self.nodes.get_mut(node).unwrap();
+            let id = snapshots.start(*state, channel).unwrap();
+            self.collect_written();
+            id
+        }
+
+        /// Sends an application message, returning the epoch it carries.
+        fn send(&mut self, src: &str, dest: &str) -> u64 {
+            self.nodes.get_mut(src).unwrap().0.send_epoch(dest)
+        }
+
+        fn receive(&mut self, src: &str, dest: &str, epoch: u64, value: u64) {
+            let (snapshots, channel, state) = self.nodes.get_mut(dest).unwrap();
+            let current = *state;
+            snapshots
+                .observe(src, epoch, &value, channel, || current)
+                .unwrap();
+            *state += value;
+            self.collect_written();
+        }
+
+        fn deliver(&mut self, message: Message) {
+            let (snapshots, channel, state) = self.nodes.get_mut(&message.dest).unwrap();
+            let current = *state;
+            snapshots
+                .handle(&message.src, &message.body.payload, channel, || current)
+                .unwrap();
+            self.collect_written();
+        }
+
+        fn deliver_all(&mut self) {
+            self.deliver_all_but(|_| false);
+        }
+
+        /// Delivers everything in transit, losing the messages `lost` picks.
+        fn deliver_all_but<F>(&mut self, lost: F)
+        where
+            F: Fn(&Payload<u64, u64>) -> bool,
+        {
+            while let Some(message) = self.in_transit.pop_front() {
+                if !lost(&message.body.payload) {
+                    self.deliver(message);
+                }
+            }
+        }
+
+        fn tick(&mut self) {
+            for (snapshots, channel, _) in self.nodes.values_mut() {
+                snapshots.tick(channel).unwrap();
+            }
+            self.collect_written();
+        }
+
+        fn completed(&mut self, node: &str) -> Vec<Outcome<u64, u64>> {
+            self.nodes.get_mut(node).unwrap().0.take_completed()
+        }
+    }
+
+    #[test]
+    fn concurrent_starts_get_the_same_cut() {
+        let mut network = Network::new(&["n1", "n2", "n3"], time::Duration::from_secs(60));
+        let epoch = network.send("n1", "n2");
+        network.receive("n1", "n2", epoch, 5);
+        let epoch = network.send("n2", "n3");
+
+        network.start("n1");
+        network.start("n3");
+        network.receive("n2", "n3", epoch, 7);
+        network.deliver_all();
+
+        let [(_, first)] = network.completed("n1").try_into().unwrap();
+        let [(_, second)] = network.completed("n3").try_into().unwrap();
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(first.states, second.states);
+        assert_eq!(first.in_flight, second.in_flight);
+        assert_eq!(first.states.values().sum::<u64>(), 5);
+        assert_eq!(
+            first.in_flight,
+            vec![InFlight {
+                src: "n2".to_string(),
+                dest: "n3".to_string(),
+                message: 7
+            }]
+        );
+    }
+
+    #[test]
+    fn messages_overtaking_markers_land_on_the_right_side() {
+        let mut network = Network::new(&["n1", "n2"], time::Duration::from_secs(60));
+        let before = network.send("n1", "n2");
+        network.start("n1");
+        let after = network.send("n1", "n2");
+
+        // the message sent after recording arrives first, then the marker, then the message
+        // sent before recording
+        network.receive("n1", "n2", after, 3);
+        network.deliver_all();
+        assert!(network.completed("n1").is_empty());
+        network.receive("n1", "n2", before, 2);
+        network.deliver_all();
+
+        let [(_, cut)] = network.completed("n1").try_into().unwrap();
+        let cut = cut.unwrap();
+        assert_eq!(cut.states["n2"], 0);
+        assert_eq!(
+            cut.in_flight,
+            vec![InFlight {
+                src: "n1".to_string(),
+                dest: "n2".to_string(),
+                message: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn lost_markers_and_reports_are_resent() {
+        let mut network = Network::new(&["n1", "n2"], time::Duration::from_secs(60));
+        network.start("n1");
+        network.in_transit.clear();
+        network.tick();
+        network.deliver_all_but(|payload| matches!(payload, Payload::SnapshotReport { .. }));
+        assert!(network.completed("n1").is_empty());
+        network.tick();
+        network.deliver_all();
+
+        let [(_, cut)] = network.completed("n1").try_into().unwrap();
+        assert_eq!(cut.unwrap().states.len(), 2);
+        network.tick();
+        assert!(network.in_transit.is_empty());
+    }
+
+    #[test]
+    fn lost_messages_time_out() {
+        let mut network = Network::new(&["n1", "n2"], time::Duration::ZERO);
+        network.send("n1", "n2");
+        let id = network.start("n1");
+        network.deliver_all();
+        assert!(network.completed("n1").is_empty());
+
+        network.tick();
+        let [(timed_out, cut)] = network.completed("n1").try_into().unwrap();
+        assert_eq!(timed_out, id);
+        assert!(cut.is_err());
+        for (snapshots, _, _) in network.nodes.values() {
+            assert!(snapshots.recording.is_empty() && snapshots.unacked.is_empty());
+        }
+    }
+}
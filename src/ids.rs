@@ -0,0 +1,570 @@
+//! Unique, roughly time-ordered identifiers.
+//!
+//! Snowflake IDs are 64-bit integers made of, from the most significant bit down:
+//!
+//! - a zero sign bit, so they fit signed integers too
+//! - 41 bits of milliseconds since [`EPOCH_MILLIS`], enough for 69 years
+//! - 10 bits of node index
+//! - 12 bits of sequence within the millisecond
+//!
+//! For interop, [`Uuid7`] and [`Ulid`] generate RFC 9562 version 7 UUIDs and ULIDs, which are
+//! 128 bits starting with 48 bits of milliseconds since the Unix epoch. Both spend some of
+//! their random bits on a sequence and on the node index, so that IDs from one node are
+//! ordered and IDs from different nodes never collide:
+//!
+//! - UUIDv7: milliseconds, version, 12 bits of sequence, variant, 10 bits of node index and
+//!   52 random bits
+//! - ULID: milliseconds, 16 bits of sequence, 10 bits of node index and 54 random bits
+//!
+//! IDs from one generator are strictly increasing. When the clock goes backwards or the
+//! sequence runs out within a millisecond, the generator keeps counting from the last
+//! millisecond it used rather than waiting, which is allowed to run ahead of the clock by at
+//! most [`MAX_DRIFT_MILLIS`].
+//!
+//! Time-based IDs can repeat after a restart if the clock is set back. A [`Reserver`] does
+//! not depend on clocks at all: it hands out counters from blocks it records as used in a
+//! file or in `lin-kv` beforehand.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path;
+use std::str::FromStr;
+use std::time;
+
+use crate::channel;
+use crate::kv;
+use crate::storage::write_synced;
+
+/// 2024-01-01T00:00:00Z.
+pub const EPOCH_MILLIS: u64 = 1_704_067_200_000;
+
+pub const TIMESTAMP_BITS: u32 = 41;
+pub const NODE_BITS: u32 = 10;
+pub const SEQUENCE_BITS: u32 = 12;
+
+pub const MAX_NODES: u64 = 1 << NODE_BITS;
+
+/// How far ahead of the clock IDs may be issued before giving up.
+pub const MAX_DRIFT_MILLIS: u64 = 1000;
+
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+const MAX_TIMESTAMP: u64 = (1 << TIMESTAMP_BITS) - 1;
+
+const UUID_SEQUENCE_BITS: u32 = 12;
+const UUID_RANDOM_BITS: u32 = 52;
+const ULID_SEQUENCE_BITS: u32 = 16;
+const ULID_RANDOM_BITS: u32 = 54;
+const MAX_UNIX_MILLIS: u64 = (1 << 48) - 1;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn check_node(node: u64) -> Result<u64, &'static str> {
+    if node >= MAX_NODES {
+        return Err("node index does not fit in the id");
+    }
+    Ok(node)
+}
+
+/// Hands out strictly increasing (millisecond, sequence) pairs.
+struct Monotonic {
+    max_sequence: u64,
+    last_millis: u64,
+    sequence: u64,
+}
+
+impl Monotonic {
+    fn new(sequence_bits: u32) -> Self {
+        Self {
+            max_sequence: (1 << sequence_bits) - 1,
+            last_millis: 0,
+            sequence: 0,
+        }
+    }
+
+    fn next(&mut self, now: u64) -> Result<(u64, u64), &'static str> {
+        if now > self.last_millis {
+            self.last_millis = now;
+            self.sequence = 0;
+        } else if self.sequence < self.max_sequence {
+            // same millisecond, or the clock went backwards
+            self.sequence += 1;
+        } else {
+            self.last_millis += 1;
+            self.sequence = 0;
+        }
+
+        if self.last_millis > now + MAX_DRIFT_MILLIS {
+            return Err("clock moved backwards too far");
+        }
+        Ok((self.last_millis, self.sequence))
+    }
+}
+
+pub struct Snowflake {
+    node: u64,
+    clock: Monotonic,
+}
+
+impl Snowflake {
+    pub fn new(node: u64) -> Result<Self, &'static str> {
+        Ok(Self {
+            node: check_node(node)?,
+            clock: Monotonic::new(SEQUENCE_BITS),
+        })
+    }
+
+    pub fn next_id(&mut self) -> Result<u64, &'static str> {
+        let now = now_millis()
+            .checked_sub(EPOCH_MILLIS)
+            .ok_or("clock is before the epoch")?;
+        let (millis, sequence) = self.clock.next(now)?;
+        if millis > MAX_TIMESTAMP {
+            return Err("timestamp does not fit in the id");
+        }
+        Ok(millis << (NODE_BITS + SEQUENCE_BITS) | self.node << SEQUENCE_BITS | sequence)
+    }
+}
+
+/// The milliseconds since the Unix epoch, node index and sequence a Snowflake ID was made of.
+pub fn parts(id: u64) -> (u64, u64, u64) {
+    let millis = (id >> (NODE_BITS + SEQUENCE_BITS)) + EPOCH_MILLIS;
+    let node = (id >> SEQUENCE_BITS) & (MAX_NODES - 1);
+    let sequence = id & MAX_SEQUENCE;
+    (millis, node, sequence)
+}
+
+fn random_bits(bits: u32) -> u128 {
+    (rand::random::<u64>() & ((1 << bits) - 1)) as u128
+}
+
+fn unix_millis(clock: &mut Monotonic) -> Result<(u128, u128), &'static str> {
+    let (millis, sequence) = clock.next(now_millis())?;
+    if millis > MAX_UNIX_MILLIS {
+        return Err("timestamp does not fit in the id");
+    }
+    Ok((millis as u128, sequence as u128))
+}
+
+pub struct Uuid7 {
+    node: u64,
+    clock: Monotonic,
+}
+
+impl Uuid7 {
+    pub fn new(node: u64) -> Result<Self, &'static str> {
+        Ok(Self {
+            node: check_node(node)?,
+            clock: Monotonic::new(UUID_SEQUENCE_BITS),
+        })
+    }
+
+    pub fn next_id(&mut self) -> Result<u128, &'static str> {
+        let (millis, sequence) = unix_millis(&mut self.clock)?;
+        Ok(millis << 80
+            | 0x7 << 76
+            | sequence << 64
+            | 0b10 << 62
+            | (self.node as u128) << UUID_RANDOM_BITS
+            | random_bits(UUID_RANDOM_BITS))
+    }
+}
+
+/// Formats a UUID as 8-4-4-4-12 lowercase hex digits.
+pub fn format_uuid(id: u128) -> String {
+    let hex = format!("{id:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+pub struct Ulid {
+    node: u64,
+    clock: Monotonic,
+}
+
+impl Ulid {
+    pub fn new(node: u64) -> Result<Self, &'static str> {
+        Ok(Self {
+            node: check_node(node)?,
+            clock: Monotonic::new(ULID_SEQUENCE_BITS),
+        })
+    }
+
+    pub fn next_id(&mut self) -> Result<u128, &'static str> {
+        let (millis, sequence) = unix_millis(&mut self.clock)?;
+        Ok(millis << 80
+            | sequence << (NODE_BITS + ULID_RANDOM_BITS)
+            | (self.node as u128) << ULID_RANDOM_BITS
+            | random_bits(ULID_RANDOM_BITS))
+    }
+}
+
+/// Encodes the lowest `5 * len` bits of `value` in Crockford's base32.
+fn crockford(value: u128, len: u32) -> String {
+    (0..len)
+        .rev()
+        .map(|i| CROCKFORD[((value >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+/// Formats a ULID as 26 characters of Crockford's base32.
+pub fn format_ulid(id: u128) -> String {
+    crockford(id, 26)
+}
+
+/// How IDs are generated and presented. Strings have a fixed width, so they sort like the
+/// IDs they encode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Number,
+    Hex,
+    /// Crockford's base32, 13 characters.
+    Base32,
+    Uuid7,
+    Ulid,
+}
+
+impl FromStr for Format {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "number" => Ok(Format::Number),
+            "hex" => Ok(Format::Hex),
+            "base32" => Ok(Format::Base32),
+            "uuidv7" => Ok(Format::Uuid7),
+            "ulid" => Ok(Format::Ulid),
+            _ => Err("unknown id format"),
+        }
+    }
+}
+
+impl Format {
+    /// Whether IDs in this format fit in 64 bits, unlike UUIDs and ULIDs.
+    pub fn is_64_bit(&self) -> bool {
+        matches!(self, Format::Number | Format::Hex | Format::Base32)
+    }
+
+    /// Formats a Snowflake ID, or any other 64-bit ID. Formats that are not `is_64_bit` show
+    /// it as a number.
+    pub fn format(&self, id: u64) -> serde_json::Value {
+        match self {
+            Format::Hex => serde_json::Value::from(format!("{id:016x}")),
+            Format::Base32 => serde_json::Value::from(crockford(id as u128, 13)),
+            _ => serde_json::Value::from(id),
+        }
+    }
+}
+
+enum Scheme {
+    Snowflake(Snowflake),
+    Uuid7(Uuid7),
+    Ulid(Ulid),
+}
+
+/// Generates IDs in a configurable format.
+pub struct Generator {
+    format: Format,
+    scheme: Scheme,
+}
+
+impl Generator {
+    pub fn new(node: u64, format: Format) -> Result<Self, &'static str> {
+        let scheme = match format {
+            Format::Uuid7 => Scheme::Uuid7(Uuid7::new(node)?),
+            Format::Ulid => Scheme::Ulid(Ulid::new(node)?),
+            _ => Scheme::Snowflake(Snowflake::new(node)?),
+        };
+        Ok(Self { format, scheme })
+    }
+
+    pub fn next_id(&mut self) -> Result<serde_json::Value, &'static str> {
+        match &mut self.scheme {
+            Scheme::Snowflake(snowflake) => Ok(self.format.format(snowflake.next_id()?)),
+            Scheme::Uuid7(uuid) => Ok(format_uuid(uuid.next_id()?).into()),
+            Scheme::Ulid(ulid) => Ok(format_ulid(ulid.next_id()?).into()),
+        }
+    }
+}
+
+/// Where the high-water mark of a [`Reserver`] is kept.
+pub enum Store {
+    /// A file on local disk, rewritten and synced before IDs from a block are handed out.
+    File(path::PathBuf),
+    /// A key of a Maelstrom key-value service, advanced with compare-and-set.
+    Kv { service: String, key: String },
+}
+
+enum Request {
+    Read,
+    Cas { to: u64 },
+}
+
+/// Hands out counters from blocks reserved ahead of use. The end of the last reserved block
+/// is stored before any counter in it is used, so a restarted node continues past every
+/// counter it could have handed out, and never reissues an ID.
+///
+/// The next block is reserved once less than a quarter of the current one is left, so that
+/// requests rarely wait for the store.
+pub struct Reserver {
+    node: u64,
+    store: Store,
+    block_size: u64,
+    timeout: time::Duration,
+
+    blocks: VecDeque<(u64, u64)>,
+    /// The high-water mark last read from or written to the store.
+    known: Option<u64>,
+    in_flight: Option<(usize, Request, time::Instant)>,
+}
+
+impl Reserver {
+    pub fn new(
+        node: u64,
+        store: Store,
+        block_size: u64,
+        timeout: time::Duration,
+    ) -> Result<Self, &'static str> {
+        Ok(Self {
+            node: check_node(node)?,
+            store,
+            block_size,
+            timeout,
+            blocks: VecDeque::new(),
+            known: None,
+            in_flight: None,
+        })
+    }
+
+    fn remaining(&self) -> u64 {
+        self.blocks.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// The next ID, or `None` if a block is still being reserved.
+    pub fn next_id(
+        &mut self,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<Option<u64>, &'static str> {
+        if self.remaining() < self.block_size / 4 {
+            self.reserve(channel)?;
+        }
+        let Some((start, end)) = self.blocks.front_mut() else {
+            return Ok(None);
+        };
+        let counter = *start;
+        *start += 1;
+        if start == end {
+            self.blocks.pop_front();
+        }
+        if counter >= 1 << (63 - NODE_BITS) {
+            return Err("counter does not fit in the id");
+        }
+        Ok(Some(counter << NODE_BITS | self.node))
+    }
+
+    fn reserve(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if self.in_flight.is_some() {
+            return Ok(());
+        }
+        match &self.store {
+            Store::File(path) => {
+                let start = match fs::read_to_string(path) {
+                    Ok(contents) => contents.trim().parse().map_err(|_| "corrupt id file")?,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                    Err(_) => return Err("cannot read id file"),
+                };
+                let end = start + self.block_size;
+                write_synced(path, &end.to_string()).map_err(|_| "cannot write id file")?;
+                self.blocks.push_back((start, end));
+                Ok(())
+            }
+            Store::Kv { .. } => match self.known {
+                Some(from) => self.cas(from, channel),
+                None => self.request(Request::Read, channel),
+            },
+        }
+    }
+
+    fn cas(
+        &mut self,
+        from: u64,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let to = from + self.block_size;
+        self.request(Request::Cas { to }, channel)
+    }
+
+    fn request(
+        &mut self,
+        request: Request,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let Store::Kv { service, key } = &self.store else {
+            return Ok(());
+        };
+        let key = serde_json::Value::from(key.as_str());
+        let payload = match request {
+            Request::Read => kv::Payload::Read { key },
+            Request::Cas { to } => kv::Payload::Cas {
+                key,
+                from: self.known.unwrap_or(0).into(),
+                to: to.into(),
+                create_if_not_exists: self.known == Some(0),
+            },
+        };
+        let msg_id = channel.next_msg_id();
+        self.in_flight = Some((msg_id, request, time::Instant::now()));
+        channel.send_with_id(&service.clone(), msg_id, &payload)
+    }
+
+    /// Handles a reply from the key-value service, returning whether a block was reserved.
+    pub fn handle(
+        &mut self,
+        in_reply_to: Option<usize>,
+        payload: &kv::Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<bool, &'static str> {
+        match &self.in_flight {
+            Some((msg_id, _, _)) if Some(*msg_id) == in_reply_to => {}
+            _ => return Ok(false),
+        }
+        let (_, request, _) = self.in_flight.take().unwrap();
+
+        match (request, payload) {
+            (Request::Read, kv::Payload::ReadOk { value }) => {
+                let known = value.as_u64().ok_or("corrupt high-water mark")?;
+                self.known = Some(known);
+                self.cas(known, channel)?;
+            }
+            (Request::Read, kv::Payload::Error { code, .. }) if *code == kv::KEY_DOES_NOT_EXIST => {
+                self.known = Some(0);
+                self.cas(0, channel)?;
+            }
+            (Request::Cas { to }, kv::Payload::CasOk) => {
+                let start = self.known.unwrap_or(0);
+                self.known = Some(to);
+                self.blocks.push_back((start, to));
+                return Ok(true);
+            }
+            // another incarnation of this node moved the mark, find out where to
+            (Request::Cas { .. }, kv::Payload::Error { .. }) => {
+                self.known = None;
+                self.request(Request::Read, channel)?;
+            }
+            _ => self.known = None,
+        }
+        Ok(false)
+    }
+
+    /// Retries reservations the store did not answer in time.
+    pub fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if let Some((_, _, sent)) = &self.in_flight {
+            if sent.elapsed() >= self.timeout {
+                // a lost compare-and-set may have gone through, read the mark again
+                self.in_flight = None;
+                self.known = None;
+            }
+        }
+        if self.remaining() < self.block_size / 4 {
+            self.reserve(channel)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODES: u64 = 4;
+    const IDS_PER_NODE: usize = 1_000_000;
+
+    /// Draws IDs from every node in turn, so that they share milliseconds, and checks that each
+    /// node's IDs strictly increase and that no ID is drawn twice.
+    fn check_scheme<G>(mut generators: Vec<G>, mut next_id: impl FnMut(&mut G) -> u128) {
+        let mut last = vec![None; generators.len()];
+        let mut all = Vec::with_capacity(generators.len() * IDS_PER_NODE);
+        for _ in 0..IDS_PER_NODE {
+            for (node, generator) in generators.iter_mut().enumerate() {
+                let id = next_id(generator);
+                if let Some(previous) = last[node] {
+                    assert!(id > previous, "node {node} went from {previous} to {id}");
+                }
+                last[node] = Some(id);
+                all.push(id);
+            }
+        }
+        all.sort_unstable();
+        let drawn = all.len();
+        all.dedup();
+        assert_eq!(all.len(), drawn, "duplicate ids");
+    }
+
+    #[test]
+    fn snowflake_ids_are_ordered_and_unique() {
+        let generators = (0..NODES).map(|n| Snowflake::new(n).unwrap()).collect();
+        check_scheme(generators, |g: &mut Snowflake| g.next_id().unwrap() as u128);
+    }
+
+    #[test]
+    fn uuid7_ids_are_ordered_and_unique() {
+        let generators = (0..NODES).map(|n| Uuid7::new(n).unwrap()).collect();
+        check_scheme(generators, |g: &mut Uuid7| g.next_id().unwrap());
+    }
+
+    #[test]
+    fn ulid_ids_are_ordered_and_unique() {
+        let generators = (0..NODES).map(|n| Ulid::new(n).unwrap()).collect();
+        check_scheme(generators, |g: &mut Ulid| g.next_id().unwrap());
+    }
+
+    /// Formatted IDs sort like the IDs they encode.
+    #[test]
+    fn formatted_ids_keep_their_order() {
+        let formats = [
+            Format::Number,
+            Format::Hex,
+            Format::Base32,
+            Format::Uuid7,
+            Format::Ulid,
+        ];
+        for format in formats {
+            let mut generators: Vec<Generator> = (0..NODES)
+                .map(|n| Generator::new(n, format).unwrap())
+                .collect();
+            let mut last: Vec<Option<serde_json::Value>> = vec![None; generators.len()];
+            let mut seen = std::collections::HashSet::new();
+            for _ in 0..100_000 {
+                for (node, generator) in generators.iter_mut().enumerate() {
+                    let id = generator.next_id().unwrap();
+                    if let Some(previous) = &last[node] {
+                        let increasing = match (previous, &id) {
+                            (serde_json::Value::Number(a), serde_json::Value::Number(b)) => {
+                                a.as_u64() < b.as_u64()
+                            }
+                            (serde_json::Value::String(a), serde_json::Value::String(b)) => a < b,
+                            _ => false,
+                        };
+                        assert!(increasing, "{format:?}: {previous} then {id}");
+                    }
+                    assert!(seen.insert(id.to_string()), "{format:?}: duplicate {id}");
+                    last[node] = Some(id);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,395 @@
+//! A set of integers stored as sorted, non-overlapping ranges.
+//!
+//! Broadcast values tend to be dense, so a set of tens of thousands of them collapses into a
+//! handful of ranges. The serialized form is a list where each item is either a single value
+//! or an inclusive `[start, end]` range, e.g. `[[0, 9999], 10005]`. Use [`IntSet::plain`] when
+//! the receiver expects a plain list of values, like Maelstrom clients do.
+//!
+//! Lists whose order matters can use the same encoding with `#[serde(with = "runs")]`, which
+//! collapses runs of consecutive ascending values instead of sorting them.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::ser::SerializeSeq;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct IntSet {
+    /// Inclusive ranges keyed by their start.
+    ranges: BTreeMap<i64, i64>,
+    /// Wider than `usize`, a set can hold every `i64`.
+    len: u128,
+}
+
+impl IntSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of values, or `usize::MAX` if there are more.
+    pub fn len(&self) -> usize {
+        usize::try_from(self.len).unwrap_or(usize::MAX)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, value: i64) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, &end)| value <= end)
+    }
+
+    /// Adds a value, returning whether it was not present yet.
+    pub fn insert(&mut self, value: i64) -> bool {
+        let len = self.len;
+        self.insert_range(value, value);
+        self.len != len
+    }
+
+    /// Adds every value from `start` to `end` inclusive.
+    pub fn insert_range(&mut self, mut start: i64, mut end: i64) {
+        if start > end {
+            return;
+        }
+
+        // absorb a range that overlaps or touches us from the left
+        if let Some((&s, &e)) = self.ranges.range(..=start).next_back() {
+            if e >= start.saturating_sub(1) {
+                if e >= end {
+                    return;
+                }
+                start = s;
+                self.remove(s);
+            }
+        }
+        // and every range starting within or right after us
+        while let Some((&s, &e)) = self.ranges.range(start..=end.saturating_add(1)).next() {
+            end = end.max(e);
+            self.remove(s);
+        }
+
+        self.ranges.insert(start, end);
+        self.len += span(start, end);
+    }
+
+    /// Adds every value of `other`.
+    pub fn union(&mut self, other: &IntSet) {
+        for (start, end) in other.ranges() {
+            self.insert_range(start, end);
+        }
+    }
+
+    /// The values of `self` that are not in `other`.
+    pub fn difference(&self, other: &IntSet) -> IntSet {
+        let mut difference = IntSet::new();
+        for (start, end) in self.ranges() {
+            let mut next = Some(start);
+            let overlapping = other
+                .ranges
+                .range(..=end)
+                .filter(|(_, &e)| e >= start)
+                .map(|(&s, &e)| (s, e));
+            for (s, e) in overlapping {
+                let Some(from) = next else {
+                    break;
+                };
+                if s > from {
+                    difference.insert_range(from, s - 1);
+                }
+                next = e.checked_add(1);
+            }
+            if let Some(from) = next {
+                difference.insert_range(from, end);
+            }
+        }
+        difference
+    }
+
+    /// The values between `start` and `end`, inclusive.
+    pub fn within(&self, start: i64, end: i64) -> IntSet {
+        let mut within = IntSet::new();
+        if start > end {
+            return within;
+        }
+        // the range starting before `start` may reach into it
+        let before = self.ranges.range(..start).next_back();
+        for (&s, &e) in before.into_iter().chain(self.ranges.range(start..=end)) {
+            within.insert_range(s.max(start), e.min(end));
+        }
+        within
+    }
+
+    /// The inclusive ranges making up the set, in ascending order.
+    pub fn ranges(&self) -> impl Iterator<Item = (i64, i64)> + '_ {
+        self.ranges.iter().map(|(&start, &end)| (start, end))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        self.ranges().flat_map(|(start, end)| start..=end)
+    }
+
+    /// A view of the set that serializes as a plain list of values.
+    pub fn plain(&self) -> Plain<'_> {
+        Plain(self)
+    }
+
+    fn remove(&mut self, start: i64) {
+        if let Some(end) = self.ranges.remove(&start) {
+            self.len -= span(start, end);
+        }
+    }
+}
+
+fn span(start: i64, end: i64) -> u128 {
+    (end as i128 - start as i128 + 1) as u128
+}
+
+impl fmt::Debug for IntSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.ranges().map(|(start, end)| start..=end))
+            .finish()
+    }
+}
+
+impl Extend<i64> for IntSet {
+    fn extend<I: IntoIterator<Item = i64>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+impl FromIterator<i64> for IntSet {
+    fn from_iter<I: IntoIterator<Item = i64>>(iter: I) -> Self {
+        let mut set = IntSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Item {
+    Single(i64),
+    Range([i64; 2]),
+}
+
+impl Serialize for IntSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.ranges.len()))?;
+        for (start, end) in self.ranges() {
+            if start == end {
+                seq.serialize_element(&Item::Single(start))?;
+            } else {
+                seq.serialize_element(&Item::Range([start, end]))?;
+            }
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for IntSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut set = IntSet::new();
+        for item in Vec::<Item>::deserialize(deserializer)? {
+            match item {
+                Item::Single(value) => set.insert_range(value, value),
+                Item::Range([start, end]) => set.insert_range(start, end),
+            }
+        }
+        Ok(set)
+    }
+}
+
+/// Serializes a list of values in order, as ranges for runs of consecutive ascending values.
+pub mod runs {
+    use serde::ser::SerializeSeq;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    use super::Item;
+
+    pub fn serialize<S: Serializer>(values: &[i64], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        let mut i = 0;
+        while i < values.len() {
+            let start = values[i];
+            let mut end = start;
+            while values
+                .get(i + 1)
+                .is_some_and(|&next| Some(next) == end.checked_add(1))
+            {
+                end += 1;
+                i += 1;
+            }
+            if start == end {
+                seq.serialize_element(&Item::Single(start))?;
+            } else {
+                seq.serialize_element(&Item::Range([start, end]))?;
+            }
+            i += 1;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<i64>, D::Error> {
+        let mut values = Vec::new();
+        for item in Vec::<Item>::deserialize(deserializer)? {
+            match item {
+                Item::Single(value) => values.push(value),
+                Item::Range([start, end]) => values.extend(start..=end),
+            }
+        }
+        Ok(values)
+    }
+}
+
+pub struct Plain<'a>(&'a IntSet);
+
+impl Serialize for Plain<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use rand::Rng;
+
+    use super::*;
+
+    const CASES: usize = 1000;
+    const OPERATIONS: usize = 50;
+
+    /// A range of up to a few values, near zero or near either end of `i64`.
+    fn random_range(rng: &mut impl Rng) -> (i64, i64) {
+        let base = match rng.gen_range(0..3) {
+            0 => i64::MIN,
+            1 => 0,
+            _ => i64::MAX - 40,
+        };
+        let start = base.saturating_add(rng.gen_range(0..40));
+        let end = start.saturating_add(rng.gen_range(-1..8));
+        (start, end)
+    }
+
+    fn random_set(rng: &mut impl Rng) -> (IntSet, BTreeSet<i64>) {
+        let mut set = IntSet::new();
+        let mut reference = BTreeSet::new();
+        for _ in 0..rng.gen_range(0..OPERATIONS) {
+            let (start, end) = random_range(rng);
+            if rng.gen_bool(0.5) {
+                set.insert(start);
+                reference.insert(start);
+            } else {
+                set.insert_range(start, end);
+                reference.extend(start..=end);
+            }
+        }
+        (set, reference)
+    }
+
+    fn assert_same(set: &IntSet, reference: &BTreeSet<i64>) {
+        assert_eq!(set.len(), reference.len());
+        assert_eq!(set.is_empty(), reference.is_empty());
+        assert!(set.iter().eq(reference.iter().copied()), "{set:?}");
+        // ranges are disjoint and do not touch, or they would have been merged
+        for ((_, end), (start, _)) in set.ranges().zip(set.ranges().skip(1)) {
+            assert!(end < start - 1, "{set:?}");
+        }
+    }
+
+    #[test]
+    fn matches_a_btree_set() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..CASES {
+            let (set, reference) = random_set(&mut rng);
+            assert_same(&set, &reference);
+            for _ in 0..OPERATIONS {
+                let (value, _) = random_range(&mut rng);
+                assert_eq!(set.contains(value), reference.contains(&value));
+            }
+
+            let json = serde_json::to_string(&set).unwrap();
+            let parsed: IntSet = serde_json::from_str(&json).unwrap();
+            assert_same(&parsed, &reference);
+        }
+    }
+
+    #[test]
+    fn union_and_difference_match_a_btree_set() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..CASES {
+            let (a, a_reference) = random_set(&mut rng);
+            let (b, b_reference) = random_set(&mut rng);
+
+            let (a_start, _) = random_range(&mut rng);
+            let (b_start, _) = random_range(&mut rng);
+            let (start, end) = (a_start.min(b_start), a_start.max(b_start));
+            let within: BTreeSet<i64> = a_reference.range(start..=end).copied().collect();
+            assert_same(&a.within(start, end), &within);
+
+            let difference = a.difference(&b);
+            assert_same(&difference, &(&a_reference - &b_reference));
+
+            let mut union = a;
+            union.union(&b);
+            assert_same(&union, &(&a_reference | &b_reference));
+        }
+    }
+
+    #[test]
+    fn runs_keep_the_order() {
+        #[derive(Serialize, Deserialize)]
+        struct Log {
+            #[serde(with = "runs")]
+            values: Vec<i64>,
+        }
+
+        let values = vec![3, 4, 5, 1, 2, 9, i64::MAX - 1, i64::MAX, i64::MIN, 7];
+        let json = serde_json::to_string(&Log {
+            values: values.clone(),
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            format!(
+                "{{\"values\":[[3,5],[1,2],9,[{},{}],{},7]}}",
+                i64::MAX - 1,
+                i64::MAX,
+                i64::MIN
+            )
+        );
+        let parsed: Log = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.values, values);
+    }
+
+    #[test]
+    fn len_saturates() {
+        let mut set = IntSet::new();
+        set.insert_range(i64::MIN, i64::MAX);
+        assert_eq!(set.len(), usize::MAX);
+        assert!(!set.is_empty());
+
+        let mut minus_one = IntSet::new();
+        minus_one.insert(0);
+        let difference = set.difference(&minus_one);
+        assert_eq!(difference.len(), usize::MAX);
+        assert!(!difference.contains(0));
+
+        let mut half = IntSet::new();
+        half.insert_range(0, i64::MAX);
+        assert_eq!(half.len() as u128, i64::MAX as u128 + 1);
+    }
+}
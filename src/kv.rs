@@ -0,0 +1,46 @@
+//! Messages understood by the key-value services Maelstrom runs next to the nodes.
+//!
+//! Requests are sent to a service like to any node, and its replies refer back to them through
+//! `in_reply_to`.
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+pub const LIN_KV: &str = "lin-kv";
+pub const SEQ_KV: &str = "seq-kv";
+pub const LWW_KV: &str = "lww-kv";
+
+pub const KEY_DOES_NOT_EXIST: u64 = 20;
+pub const PRECONDITION_FAILED: u64 = 22;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    /// Creates the key with `to` if it is missing and `create_if_not_exists` is set.
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: u64,
+        #[serde(default)]
+        text: String,
+    },
+}
@@ -1,17 +1,47 @@
 use std::io;
+use std::path;
 use std::sync::mpsc;
 use std::thread;
 
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 
+pub mod adaptive;
+pub mod causal;
 pub mod channel;
+pub mod clock;
+pub mod election;
+pub mod failure_detector;
+pub mod global_snapshot;
+pub mod ids;
 mod init;
+pub mod intset;
+pub mod kv;
+pub mod membership;
 pub mod message;
+pub mod plumtree;
+pub mod reconcile;
+pub mod reliable;
+pub mod router;
+pub mod rumor;
+pub mod storage;
+pub mod topology;
+pub mod total_order;
+pub mod tso;
+pub mod twopc;
+pub mod version_vector;
+
+pub use chidori_derive::handler;
+pub use chidori_derive::MaelstromPayload;
 
 pub enum Event {
     Message(String),
     Tick,
+    /// Asks for a snapshot of the handler, if it runs under `main_loop_with_snapshots`.
+    Snapshot,
+    /// Sent once stdin is closed, which ends the main loop.
+    Shutdown,
 }
 
 pub trait Handler<T> {
@@ -26,6 +56,14 @@ pub trait Handler<T> {
     fn send_events(&self, send_channel: &mpsc::Sender<Event>);
 }
 
+/// A payload enum whose requests are paired with their `_ok` responses, usually derived.
+pub trait MaelstromPayload: Serialize + DeserializeOwned {
+    fn message_type(&self) -> &'static str;
+
+    /// The type of the response to this payload, if it is a request with a paired response.
+    fn response_type(&self) -> Option<&'static str>;
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
@@ -34,6 +72,19 @@ enum Init {
     InitOk,
 }
 
+/// Requests the runtime handles instead of the handler.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Control {
+    /// Writes a snapshot right away, under `main_loop_with_snapshots`.
+    SaveSnapshot,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename = "save_snapshot_ok")]
+struct SaveSnapshotOk {}
+
 fn send_events_from_stdin(tx: &mpsc::Sender<Event>) {
     let tx = tx.clone();
     thread::spawn(move || {
@@ -41,6 +92,7 @@ fn send_events_from_stdin(tx: &mpsc::Sender<Event>) {
         for line in lines {
             tx.send(Event::Message(line.unwrap())).unwrap();
         }
+        tx.send(Event::Shutdown).unwrap();
     });
 }
 
@@ -63,9 +115,59 @@ pub fn main_loop<TNode, TPayload>(node: &mut TNode) -> io::Result<()>
 where
     TNode: Handler<TPayload>,
     for<'a> TPayload: Deserialize<'a> + Send,
+{
+    let message_channel = create_channel_from_init()?;
+    run(node, message_channel, None::<fn(&TNode) -> io::Result<()>>)
+}
+
+/// Like `main_loop`, but stamps messages between nodes with `clock` and advances it on
+/// receipt. Handlers get at it through `MessageChannel::clock`.
+pub fn main_loop_with_clock<TNode, TPayload, TClock>(
+    node: &mut TNode,
+    clock: TClock,
+) -> io::Result<()>
+where
+    TNode: Handler<TPayload>,
+    for<'a> TPayload: Deserialize<'a> + Send,
+    TClock: clock::Clock + 'static,
 {
     let mut message_channel = create_channel_from_init()?;
+    message_channel.set_clock(clock);
+    run(node, message_channel, None::<fn(&TNode) -> io::Result<()>>)
+}
+
+/// Like `main_loop`, but restores the handler from `path` right after `init` if a snapshot is
+/// there, and writes one to it on `Event::Snapshot`, on a `save_snapshot` message and on
+/// shutdown.
+pub fn main_loop_with_snapshots<TNode, TPayload>(
+    node: &mut TNode,
+    path: &path::Path,
+) -> io::Result<()>
+where
+    TNode: Handler<TPayload> + Serialize + DeserializeOwned,
+    for<'a> TPayload: Deserialize<'a> + Send,
+{
+    let message_channel = create_channel_from_init()?;
+    if let Some(restored) = storage::restore(path).map_err(io::Error::other)? {
+        *node = restored;
+    }
+    run(
+        node,
+        message_channel,
+        Some(|node: &TNode| storage::save(path, node).map_err(io::Error::other)),
+    )
+}
 
+fn run<TNode, TPayload, F>(
+    node: &mut TNode,
+    mut message_channel: channel::MessageChannel,
+    mut snapshot: Option<F>,
+) -> io::Result<()>
+where
+    TNode: Handler<TPayload>,
+    F: FnMut(&TNode) -> io::Result<()>,
+    for<'a> TPayload: Deserialize<'a> + Send,
+{
     let (tx, rx) = mpsc::channel();
     send_events_from_stdin(&tx);
     node.send_events(&tx);
@@ -73,12 +175,30 @@ where
     for event in rx {
         match event {
             Event::Message(string) => {
-                let message = serde_json::from_str(&string).unwrap();
+                if let Some(snapshot) = &mut snapshot {
+                    let control = serde_json::from_str::<message::Message<Control>>(&string);
+                    if let Ok(save) = control {
+                        snapshot(node)?;
+                        message_channel.reply(&save, &SaveSnapshotOk {}).unwrap();
+                        continue;
+                    }
+                }
+                let message: message::Message<TPayload> = serde_json::from_str(&string).unwrap();
+                if message_channel.observe(&message).is_err() {
+                    // without its stamp the message could be handled out of causal order
+                    continue;
+                }
                 node.handle_message(&message, &mut message_channel).unwrap();
             }
             Event::Tick => {
                 node.handle_tick(&mut message_channel).unwrap();
             }
+            Event::Snapshot => {
+                if let Some(snapshot) = &mut snapshot {
+                    snapshot(node)?;
+                }
+            }
+            Event::Shutdown => return snapshot.map_or(Ok(()), |mut snapshot| snapshot(node)),
         };
     }
     Ok(())
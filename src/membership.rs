@@ -0,0 +1,505 @@
+//! SWIM-style group membership.
+//!
+//! Every protocol period a node pings one member. If it does not answer in time, `k` other
+//! members are asked to ping it on our behalf, and if that fails too the member is suspected.
+//! Suspected members that do not refute the suspicion by bumping their incarnation number are
+//! eventually declared dead. Membership updates travel piggybacked on protocol messages, and
+//! on any other message a workload wants to carry them on.
+//!
+//! Dead members are still pinged every few protocol periods, along with the rumor of their
+//! death, so that once a partition heals they can refute it and rejoin the group.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::time;
+
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+
+const MAX_PIGGYBACKED_UPDATES: usize = 8;
+const RETRANSMIT_MULTIPLIER: usize = 3;
+const SUSPICION_PERIODS: u32 = 5;
+const DEAD_PROBE_PERIODS: u32 = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Update {
+    pub node: String,
+    pub state: State,
+    pub incarnation: u64,
+}
+
+impl Update {
+    /// Whether this update carries newer information than what is known about a member.
+    fn overrides(&self, state: State, incarnation: u64) -> bool {
+        match (self.state, state) {
+            (State::Alive, _) => self.incarnation > incarnation,
+            (State::Suspect, State::Alive) => self.incarnation >= incarnation,
+            (State::Suspect, _) => self.incarnation > incarnation,
+            (State::Dead, State::Dead) => false,
+            (State::Dead, _) => self.incarnation >= incarnation,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    SwimPing {
+        seq: u64,
+        updates: Vec<Update>,
+    },
+    /// Asks the receiver to ping `target` and relay its ack.
+    SwimPingReq {
+        seq: u64,
+        target: String,
+        updates: Vec<Update>,
+    },
+    SwimAck {
+        seq: u64,
+        target: String,
+        updates: Vec<Update>,
+    },
+}
+
+struct Member {
+    state: State,
+    incarnation: u64,
+    since: time::Instant,
+}
+
+struct Probe {
+    target: String,
+    seq: u64,
+    started: time::Instant,
+    acked: bool,
+    indirect: bool,
+}
+
+pub struct Membership {
+    protocol_period: time::Duration,
+    indirect_probes: usize,
+
+    incarnation: u64,
+    members: HashMap<String, Member>,
+
+    next_seq: u64,
+    probe: Option<Probe>,
+    probe_order: Vec<String>,
+    /// Protocol periods until a dead member is pinged again.
+    dead_probe_countdown: u32,
+    /// Pings sent on behalf of another member: our seq to the requester and its seq.
+    relays: HashMap<u64, (String, u64, time::Instant)>,
+
+    dissemination: Vec<(Update, usize)>,
+    subscribers: Vec<mpsc::Sender<Update>>,
+}
+
+impl Membership {
+    /// Creates a membership view that probes one member every `protocol_period`, asking
+    /// `indirect_probes` other members for help when a probe goes unanswered.
+    pub fn new(protocol_period: time::Duration, indirect_probes: usize) -> Self {
+        Self {
+            protocol_period,
+            indirect_probes,
+            incarnation: 0,
+            members: HashMap::new(),
+            next_seq: 0,
+            probe: None,
+            probe_order: Vec::new(),
+            dead_probe_countdown: DEAD_PROBE_PERIODS,
+            relays: HashMap::new(),
+            dissemination: Vec::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Returns a receiver that gets every membership change from now on.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<Update> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// The nodes currently considered part of the group, including suspected ones.
+    pub fn members(&self) -> Vec<String> {
+        let mut members: Vec<String> = self
+            .members
+            .iter()
+            .filter(|(_, m)| m.state != State::Dead)
+            .map(|(node, _)| node.clone())
+            .collect();
+        members.sort();
+        members
+    }
+
+    pub fn state(&self, node: &str) -> Option<State> {
+        self.members.get(node).map(|m| m.state)
+    }
+
+    /// Membership updates to piggyback on an outgoing message.
+    pub fn piggyback(&mut self) -> Vec<Update> {
+        // the least disseminated updates go first
+        self.dissemination
+            .sort_by_key(|(_, remaining)| cmp::Reverse(*remaining));
+        let updates = self
+            .dissemination
+            .iter_mut()
+            .take(MAX_PIGGYBACKED_UPDATES)
+            .map(|(update, remaining)| {
+                *remaining -= 1;
+                update.clone()
+            })
+            .collect();
+        self.dissemination.retain(|(_, remaining)| *remaining > 0);
+        updates
+    }
+
+    /// Applies membership updates piggybacked on an incoming message.
+    pub fn apply(&mut self, updates: &[Update], channel: &channel::MessageChannel) {
+        self.join(channel);
+        for update in updates {
+            if update.node == channel.node_id {
+                if update.state != State::Alive {
+                    // refute the rumor about ourselves; a stale one still means the sender
+                    // has not heard of our current incarnation
+                    self.incarnation = self.incarnation.max(update.incarnation + 1);
+                    self.disseminate(Update {
+                        node: channel.node_id.clone(),
+                        state: State::Alive,
+                        incarnation: self.incarnation,
+                    });
+                }
+                continue;
+            }
+
+            let overrides = match self.members.get(&update.node) {
+                Some(member) => update.overrides(member.state, member.incarnation),
+                None => true,
+            };
+            if overrides {
+                self.set(update.clone());
+            }
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        src: &str,
+        payload: &Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        self.join(channel);
+
+        match payload {
+            Payload::SwimPing { seq, updates } => {
+                self.apply(updates, channel);
+                self.remind_if_dead(src);
+                let ack = Payload::SwimAck {
+                    seq: *seq,
+                    target: channel.node_id.clone(),
+                    updates: self.piggyback(),
+                };
+                channel.send(src, &ack)?;
+            }
+            Payload::SwimPingReq {
+                seq,
+                target,
+                updates,
+            } => {
+                self.apply(updates, channel);
+                let relay_seq = self.next_seq();
+                let relay = (src.to_string(), *seq, time::Instant::now());
+                self.relays.insert(relay_seq, relay);
+                let ping = Payload::SwimPing {
+                    seq: relay_seq,
+                    updates: self.piggyback(),
+                };
+                channel.send(target, &ping)?;
+            }
+            Payload::SwimAck {
+                seq,
+                target,
+                updates,
+            } => {
+                self.apply(updates, channel);
+                self.remind_if_dead(src);
+                if let Some((requester, requester_seq, _)) = self.relays.remove(seq) {
+                    let ack = Payload::SwimAck {
+                        seq: requester_seq,
+                        target: target.clone(),
+                        updates: self.piggyback(),
+                    };
+                    channel.send(&requester, &ack)?;
+                } else if let Some(probe) = self.probe.as_mut() {
+                    if probe.seq == *seq && probe.target == *target {
+                        probe.acked = true;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drives the protocol: finishes the current probe, starts the next one, and declares
+    /// members dead once their suspicion times out.
+    pub fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        self.join(channel);
+
+        let ack_timeout = self.protocol_period / 3;
+        if let Some(probe) = self.probe.as_mut() {
+            let elapsed = probe.started.elapsed();
+            if !probe.acked && !probe.indirect && elapsed >= ack_timeout {
+                probe.indirect = true;
+                let (seq, target) = (probe.seq, probe.target.clone());
+                self.request_indirect(seq, &target, channel)?;
+            }
+        }
+
+        let period_over = self
+            .probe
+            .as_ref()
+            .is_none_or(|probe| probe.started.elapsed() >= self.protocol_period);
+        if period_over {
+            if let Some(probe) = self.probe.take() {
+                if !probe.acked {
+                    self.suspect(&probe.target);
+                }
+            }
+            self.start_probe(channel)?;
+            self.probe_dead(channel)?;
+        }
+
+        // relayed pings whose target never answered
+        let protocol_period = self.protocol_period;
+        self.relays
+            .retain(|_, (_, _, sent)| sent.elapsed() < protocol_period);
+
+        let suspicion_timeout = self.protocol_period * SUSPICION_PERIODS;
+        let expired: Vec<(String, u64)> = self
+            .members
+            .iter()
+            .filter(|(_, m)| m.state == State::Suspect && m.since.elapsed() >= suspicion_timeout)
+            .map(|(node, m)| (node.clone(), m.incarnation))
+            .collect();
+        for (node, incarnation) in expired {
+            let update = Update {
+                node,
+                state: State::Dead,
+                incarnation,
+            };
+            self.set(update);
+        }
+        Ok(())
+    }
+
+    /// Seeds the view with the nodes from `init` the first time the channel is seen.
+    fn join(&mut self, channel: &channel::MessageChannel) {
+        if !self.members.is_empty() {
+            return;
+        }
+        for node in &channel.node_ids {
+            if *node != channel.node_id {
+                let member = Member {
+                    state: State::Alive,
+                    incarnation: 0,
+                    since: time::Instant::now(),
+                };
+                self.members.insert(node.clone(), member);
+            }
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    fn start_probe(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if self.probe_order.is_empty() {
+            // round-robin over a fresh random permutation, as in the SWIM paper
+            self.probe_order = self.members();
+            self.probe_order.shuffle(&mut rand::thread_rng());
+        }
+        let Some(target) = self.probe_order.pop() else {
+            return Ok(());
+        };
+
+        let seq = self.next_seq();
+        let ping = Payload::SwimPing {
+            seq,
+            updates: self.piggyback(),
+        };
+        channel.send(&target, &ping)?;
+        self.probe = Some(Probe {
+            target,
+            seq,
+            started: time::Instant::now(),
+            acked: false,
+            indirect: false,
+        });
+        Ok(())
+    }
+
+    /// Every few periods, tells a random dead member it was declared dead. Its ack is not
+    /// awaited: if it is reachable again it refutes, and the refutation revives it.
+    fn probe_dead(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        self.dead_probe_countdown -= 1;
+        if self.dead_probe_countdown > 0 {
+            return Ok(());
+        }
+        self.dead_probe_countdown = DEAD_PROBE_PERIODS;
+
+        let dead: Vec<(&String, u64)> = self
+            .members
+            .iter()
+            .filter(|(_, m)| m.state == State::Dead)
+            .map(|(node, m)| (node, m.incarnation))
+            .collect();
+        let Some(&(target, incarnation)) = dead.choose(&mut rand::thread_rng()) else {
+            return Ok(());
+        };
+        let target = target.clone();
+
+        let seq = self.next_seq();
+        let mut updates = vec![Update {
+            node: target.clone(),
+            state: State::Dead,
+            incarnation,
+        }];
+        updates.extend(self.piggyback());
+        channel.send(&target, &Payload::SwimPing { seq, updates })
+    }
+
+    fn request_indirect(
+        &mut self,
+        seq: u64,
+        target: &str,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let candidates: Vec<String> = self
+            .members()
+            .into_iter()
+            .filter(|node| node != target)
+            .collect();
+        let mut rng = rand::thread_rng();
+        for node in candidates.choose_multiple(&mut rng, self.indirect_probes) {
+            let request = Payload::SwimPingReq {
+                seq,
+                target: target.to_string(),
+                updates: self.piggyback(),
+            };
+            channel.send(node, &request)?;
+        }
+        Ok(())
+    }
+
+    fn suspect(&mut self, node: &str) {
+        let Some(member) = self.members.get(node) else {
+            return;
+        };
+        if member.state == State::Alive {
+            let update = Update {
+                node: node.to_string(),
+                state: State::Suspect,
+                incarnation: member.incarnation,
+            };
+            self.set(update);
+        }
+    }
+
+    /// A member we declared dead is still talking to us, so tell it to refute.
+    fn remind_if_dead(&mut self, node: &str) {
+        if let Some(member) = self.members.get(node) {
+            if member.state == State::Dead {
+                let update = Update {
+                    node: node.to_string(),
+                    state: State::Dead,
+                    incarnation: member.incarnation,
+                };
+                self.disseminate(update);
+            }
+        }
+    }
+
+    fn set(&mut self, update: Update) {
+        let member = Member {
+            state: update.state,
+            incarnation: update.incarnation,
+            since: time::Instant::now(),
+        };
+        self.members.insert(update.node.clone(), member);
+        self.subscribers
+            .retain(|subscriber| subscriber.send(update.clone()).is_ok());
+        self.disseminate(update);
+    }
+
+    fn disseminate(&mut self, update: Update) {
+        self.dissemination.retain(|(u, _)| u.node != update.node);
+        let retransmits = RETRANSMIT_MULTIPLIER * (self.members.len() + 1).ilog2().max(1) as usize;
+        self.dissemination.push((update, retransmits));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init::Init;
+
+    fn channel(node_id: &str) -> channel::MessageChannel {
+        channel::MessageChannel::from(&Init {
+            node_id: node_id.to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        })
+    }
+
+    fn rumor(node: &str, state: State, incarnation: u64) -> Update {
+        Update {
+            node: node.to_string(),
+            state,
+            incarnation,
+        }
+    }
+
+    #[test]
+    fn dead_member_is_revived_by_its_refutation() {
+        let (c1, mut c2) = (channel("n1"), channel("n2"));
+        let mut n1 = Membership::new(time::Duration::from_secs(1), 1);
+        let mut n2 = Membership::new(time::Duration::from_secs(1), 1);
+        n1.apply(&[rumor("n2", State::Dead, 0)], &c1);
+        assert_eq!(n1.members(), Vec::<String>::new());
+
+        // the ping to a dead member carries the rumor of its death
+        let ping = Payload::SwimPing {
+            seq: 0,
+            updates: vec![rumor("n2", State::Dead, 0)],
+        };
+        n2.handle("n1", &ping, &mut c2).unwrap();
+        n1.apply(&n2.piggyback(), &c1);
+        assert_eq!(n1.state("n2"), Some(State::Alive));
+        assert_eq!(n1.members(), vec!["n2".to_string()]);
+    }
+
+    #[test]
+    fn stale_rumor_is_refuted_with_the_current_incarnation() {
+        let c2 = channel("n2");
+        let mut n2 = Membership::new(time::Duration::from_secs(1), 1);
+        n2.apply(&[rumor("n2", State::Suspect, 3)], &c2);
+        n2.piggyback();
+        n2.apply(&[rumor("n2", State::Dead, 1)], &c2);
+        assert!(n2.piggyback().contains(&rumor("n2", State::Alive, 4)));
+    }
+}
@@ -1,7 +1,7 @@
 use serde::Deserialize;
 use serde::Serialize;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct MessageBody<T> {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
@@ -11,11 +11,16 @@ pub struct MessageBody<T> {
     #[serde(default)]
     pub in_reply_to: Option<usize>,
 
+    /// The timestamp of the sender's clock, on messages between nodes that run one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub stamp: Option<serde_json::Value>,
+
     #[serde(flatten)]
     pub payload: T,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Message<T> {
     /// A string identifying the node this message came from
     pub src: String,
@@ -26,3 +31,19 @@ pub struct Message<T> {
     /// An object: the payload of the message
     pub body: MessageBody<T>,
 }
+
+impl<T> Message<T> {
+    /// The same message carrying `payload` instead, e.g. the payload inside an envelope.
+    pub fn with_payload<U>(&self, payload: U) -> Message<U> {
+        Message {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            body: MessageBody {
+                msg_id: self.body.msg_id,
+                in_reply_to: self.body.in_reply_to,
+                stamp: self.body.stamp.clone(),
+                payload,
+            },
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Plumtree epidemic broadcast trees.
+//!
+//! Values are pushed eagerly along a spanning tree and announced lazily, as `ihave` digests, to
+//! the remaining peers. Receiving a value twice means the tree has a redundant edge, which is
+//! pruned to lazy. Hearing about a value that never arrives means the tree is broken, and the
+//! announcer is grafted back to eager and asked for it. The tree thus emerges from the first
+//! broadcasts and heals itself when links fail.
+//!
+//! Announcements can be lost during a partition just like values, so every peer also gets the
+//! full set announced every once in a while.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+use crate::intset::IntSet;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    PlumtreeGossip { value: i64 },
+    PlumtreeIhave { values: IntSet },
+    PlumtreeGraft { values: IntSet },
+    PlumtreePrune,
+}
+
+struct Missing {
+    announcers: VecDeque<String>,
+    deadline: time::Instant,
+}
+
+pub struct Plumtree {
+    graft_timeout: time::Duration,
+    announce_interval: time::Duration,
+
+    values: IntSet,
+    eager: BTreeSet<String>,
+    lazy: BTreeSet<String>,
+
+    announcements: HashMap<String, IntSet>,
+    missing: BTreeMap<i64, Missing>,
+    last_announced: time::Instant,
+}
+
+impl Plumtree {
+    /// Creates a broadcast tree that grafts a peer when a value it announced did not arrive
+    /// within `graft_timeout`, and announces the full set to every peer each
+    /// `announce_interval`.
+    pub fn new(graft_timeout: time::Duration, announce_interval: time::Duration) -> Self {
+        Self {
+            graft_timeout,
+            announce_interval,
+            values: IntSet::new(),
+            eager: BTreeSet::new(),
+            lazy: BTreeSet::new(),
+            announcements: HashMap::new(),
+            missing: BTreeMap::new(),
+            last_announced: time::Instant::now(),
+        }
+    }
+
+    pub fn values(&self) -> &IntSet {
+        &self.values
+    }
+
+    /// Replaces the peers to broadcast to. Every peer starts out in the tree.
+    pub fn set_peers(&mut self, peers: &[String]) {
+        self.eager = peers.iter().cloned().collect();
+        self.lazy.clear();
+    }
+
+    /// Broadcasts a value originating at this node, returning whether it was new.
+    pub fn broadcast(
+        &mut self,
+        value: i64,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<bool, &'static str> {
+        // a value announced to us may reach us from a client first
+        self.missing.remove(&value);
+        if !self.values.insert(value) {
+            return Ok(false);
+        }
+        self.push(value, None, channel)?;
+        Ok(true)
+    }
+
+    pub fn handle(
+        &mut self,
+        src: &str,
+        payload: &Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        match payload {
+            Payload::PlumtreeGossip { value } => {
+                // known either way now, stop grafting for it
+                self.missing.remove(value);
+                if self.values.insert(*value) {
+                    self.make_eager(src);
+                    self.push(*value, Some(src), channel)?;
+                } else {
+                    // a redundant path, take it out of the tree
+                    self.make_lazy(src);
+                    channel.send(src, &Payload::PlumtreePrune)?;
+                }
+            }
+            Payload::PlumtreeIhave { values } => {
+                let deadline = time::Instant::now() + self.graft_timeout;
+                for value in values.difference(&self.values).iter() {
+                    let missing = self.missing.entry(value).or_insert_with(|| Missing {
+                        announcers: VecDeque::new(),
+                        deadline,
+                    });
+                    if !missing.announcers.iter().any(|a| a == src) {
+                        missing.announcers.push_back(src.to_string());
+                    }
+                }
+            }
+            Payload::PlumtreeGraft { values } => {
+                self.make_eager(src);
+                for value in values.iter() {
+                    if self.values.contains(value) {
+                        channel.send(src, &Payload::PlumtreeGossip { value })?;
+                    }
+                }
+            }
+            Payload::PlumtreePrune => self.make_lazy(src),
+        }
+        Ok(())
+    }
+
+    /// Flushes pending announcements and grafts peers whose announced values never arrived.
+    pub fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if self.last_announced.elapsed() >= self.announce_interval {
+            self.last_announced = time::Instant::now();
+            for peer in self.eager.iter().chain(self.lazy.iter()) {
+                self.announcements.insert(peer.clone(), self.values.clone());
+            }
+        }
+        for (peer, values) in self.announcements.drain() {
+            if !values.is_empty() {
+                channel.send(&peer, &Payload::PlumtreeIhave { values })?;
+            }
+        }
+
+        let now = time::Instant::now();
+        let mut grafts: HashMap<String, IntSet> = HashMap::new();
+        for (value, missing) in self.missing.iter_mut() {
+            if missing.deadline > now {
+                continue;
+            }
+            // ask the announcers one at a time, waiting a little less for each of them
+            let Some(announcer) = missing.announcers.pop_front() else {
+                continue;
+            };
+            grafts.entry(announcer.clone()).or_default().insert(*value);
+            missing.announcers.push_back(announcer);
+            missing.deadline = now + self.graft_timeout / 2;
+        }
+        for (peer, values) in grafts {
+            self.make_eager(&peer);
+            channel.send(&peer, &Payload::PlumtreeGraft { values })?;
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        value: i64,
+        from: Option<&str>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        for peer in &self.eager {
+            if Some(peer.as_str()) != from {
+                channel.send(peer, &Payload::PlumtreeGossip { value })?;
+            }
+        }
+        for peer in &self.lazy {
+            if Some(peer.as_str()) != from {
+                self.announcements
+                    .entry(peer.clone())
+                    .or_default()
+                    .insert(value);
+            }
+        }
+        Ok(())
+    }
+
+    fn make_eager(&mut self, peer: &str) {
+        self.lazy.remove(peer);
+        self.eager.insert(peer.to_string());
+    }
+
+    fn make_lazy(&mut self, peer: &str) {
+        self.eager.remove(peer);
+        self.lazy.insert(peer.to_string());
+    }
+}
@@ -0,0 +1,415 @@
+//! Anti-entropy by set reconciliation.
+//!
+//! The value space is split into a tree of aligned ranges, each one `FANOUT` times smaller than
+//! its parent, down to single values. Every range has a digest: the number of values in it and
+//! the XOR of their hashes. Peers first compare the digest of the whole set, which is all that
+//! is sent once they agree. On a mismatch they walk down the ranges whose digests differ, and
+//! once either side has few values left in a range it sends them over, so what is exchanged is
+//! the set difference along with a few values per differing range:
+//!
+//! 1. A sends `reconcile_digest` with the digest of its whole set and the smallest range
+//!    covering it
+//! 2. If they differ, B starts from the smallest range covering both sets
+//! 3. Each side answers with `reconcile_ranges`, splitting the ranges that differ and holding
+//!    the digests of their non-empty subranges, or with `reconcile_diff` holding its values in
+//!    them once they are small enough
+//! 4. The other side merges them and answers with `reconcile_values` holding what it had in
+//!    those ranges that the sender was missing
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+use crate::intset::IntSet;
+
+/// Bits of the value space covered by each level of the tree.
+const FANOUT_BITS: u32 = 4;
+const FANOUT: i64 = 1 << FANOUT_BITS;
+/// The level of the range covering every value. Single values are at level 0.
+const ROOT_LEVEL: u8 = (i64::BITS / FANOUT_BITS) as u8;
+
+/// Ranges with at most this many values on either side are sent whole.
+const MAX_DIFF_VALUES: u64 = 64;
+
+/// A range of the tree: its values are the ones whose `level * FANOUT_BITS` high bits are
+/// `index`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Range {
+    pub level: u8,
+    pub index: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Digest {
+    pub count: u64,
+    pub hash: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RangeDigest {
+    #[serde(flatten)]
+    pub range: Range,
+    #[serde(flatten)]
+    pub digest: Digest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    ReconcileDigest {
+        root: u64,
+        len: usize,
+        covering: Range,
+    },
+    ReconcileRanges {
+        split: Vec<Range>,
+        /// Subranges of `split` missing here are empty.
+        digests: Vec<RangeDigest>,
+    },
+    ReconcileDiff {
+        ranges: Vec<Range>,
+        values: IntSet,
+    },
+    ReconcileValues {
+        values: IntSet,
+    },
+}
+
+/// splitmix64, so every node hashes values the same way.
+fn hash(value: i64) -> u64 {
+    let mut z = (value as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The contribution of a value to a digest, kept within the 53 bits that JSON numbers
+/// represent exactly.
+fn digest(value: i64) -> u64 {
+    hash(value) >> 11
+}
+
+impl Range {
+    const ROOT: Range = Range {
+        level: ROOT_LEVEL,
+        index: 0,
+    };
+
+    fn containing(value: i64, level: u8) -> Range {
+        if level >= ROOT_LEVEL {
+            return Range::ROOT;
+        }
+        Range {
+            level,
+            index: value >> (level as u32 * FANOUT_BITS),
+        }
+    }
+
+    /// The smallest range holding both `self` and `other`.
+    fn common(&self, other: &Range) -> Range {
+        let (a, b) = (self.bounds().0, other.bounds().0);
+        (self.level.max(other.level)..ROOT_LEVEL)
+            .map(|level| (Range::containing(a, level), Range::containing(b, level)))
+            .find(|(a, b)| a == b)
+            .map_or(Range::ROOT, |(a, _)| a)
+    }
+
+    /// The inclusive bounds of the range.
+    fn bounds(&self) -> (i64, i64) {
+        if self.level >= ROOT_LEVEL {
+            return (i64::MIN, i64::MAX);
+        }
+        let shift = self.level as u32 * FANOUT_BITS;
+        let start = self.index << shift;
+        (start, start + ((1i64 << shift) - 1))
+    }
+
+    fn children(&self) -> impl Iterator<Item = Range> {
+        let level = self.level - 1;
+        // the top level splits on the sign bit too
+        let indices = match self.level {
+            ROOT_LEVEL => -FANOUT / 2..=FANOUT / 2 - 1,
+            _ => self.index * FANOUT..=self.index * FANOUT + (FANOUT - 1),
+        };
+        indices.map(move |index| Range { level, index })
+    }
+}
+
+/// A set of values along with the digests needed to reconcile it with peers.
+#[derive(Serialize, Deserialize)]
+#[serde(from = "Stored")]
+pub struct Reconciler {
+    values: IntSet,
+    /// The digests of the non-empty ranges between single values and the root.
+    #[serde(skip)]
+    digests: HashMap<Range, Digest>,
+    #[serde(skip)]
+    root: u64,
+}
+
+/// What a snapshot of a reconciler holds, the digests follow from the values.
+#[derive(Deserialize)]
+struct Stored {
+    values: IntSet,
+}
+
+impl From<Stored> for Reconciler {
+    fn from(stored: Stored) -> Self {
+        let mut reconciler = Reconciler::new();
+        reconciler.union(&stored.values);
+        reconciler
+    }
+}
+
+impl Default for Reconciler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reconciler {
+    pub fn new() -> Self {
+        Self {
+            values: IntSet::new(),
+            digests: HashMap::new(),
+            root: 0,
+        }
+    }
+
+    pub fn values(&self) -> &IntSet {
+        &self.values
+    }
+
+    /// Adds a value, returning whether it was not present yet.
+    pub fn insert(&mut self, value: i64) -> bool {
+        if !self.values.insert(value) {
+            return false;
+        }
+        let hash = digest(value);
+        for level in 1..ROOT_LEVEL {
+            let digest = self
+                .digests
+                .entry(Range::containing(value, level))
+                .or_default();
+            digest.count += 1;
+            digest.hash ^= hash;
+        }
+        self.root ^= hash;
+        true
+    }
+
+    pub fn union(&mut self, values: &IntSet) {
+        for value in values.difference(&self.values).iter() {
+            self.insert(value);
+        }
+    }
+
+    fn digest(&self, range: Range) -> Digest {
+        match range.level {
+            0 if self.values.contains(range.index) => Digest {
+                count: 1,
+                hash: digest(range.index),
+            },
+            0 => Digest::default(),
+            ROOT_LEVEL.. => Digest {
+                count: self.values.len() as u64,
+                hash: self.root,
+            },
+            _ => self.digests.get(&range).copied().unwrap_or_default(),
+        }
+    }
+
+    /// The smallest range holding every value.
+    fn covering(&self) -> Range {
+        let (Some((min, _)), Some((_, max))) =
+            (self.values.ranges().next(), self.values.ranges().last())
+        else {
+            return Range::containing(0, 0);
+        };
+        Range::containing(min, 0).common(&Range::containing(max, 0))
+    }
+
+    fn in_ranges(&self, ranges: &[Range]) -> IntSet {
+        let mut values = IntSet::new();
+        for range in ranges {
+            let (start, end) = range.bounds();
+            values.union(&self.values.within(start, end));
+        }
+        values
+    }
+
+    /// Starts a reconciliation round with `peer`.
+    pub fn start(
+        &self,
+        peer: &str,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let digest = Payload::ReconcileDigest {
+            root: self.root,
+            len: self.values.len(),
+            covering: self.covering(),
+        };
+        channel.send(peer, &digest)
+    }
+
+    /// Answers the digests of a peer: ranges that differ are either split further or, once
+    /// small, sent whole.
+    fn compare<I>(
+        &self,
+        src: &str,
+        theirs: I,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str>
+    where
+        I: IntoIterator<Item = (Range, Digest)>,
+    {
+        let mut split = Vec::new();
+        let mut digests = Vec::new();
+        let mut whole = Vec::new();
+        for (range, theirs) in theirs {
+            if range.level > ROOT_LEVEL {
+                continue;
+            }
+            let ours = self.digest(range);
+            if ours == theirs {
+                continue;
+            }
+            if range.level <= 1 || ours.count.min(theirs.count) <= MAX_DIFF_VALUES {
+                whole.push(range);
+                continue;
+            }
+            split.push(range);
+            for range in range.children() {
+                let digest = self.digest(range);
+                if digest.count > 0 {
+                    digests.push(RangeDigest { range, digest });
+                }
+            }
+        }
+        if !split.is_empty() {
+            channel.send(src, &Payload::ReconcileRanges { split, digests })?;
+        }
+        if !whole.is_empty() {
+            let values = self.in_ranges(&whole);
+            channel.send(
+                src,
+                &Payload::ReconcileDiff {
+                    ranges: whole,
+                    values,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn handle(
+        &mut self,
+        src: &str,
+        payload: &Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        match payload {
+            Payload::ReconcileDigest {
+                root,
+                len,
+                covering,
+            } => {
+                if *root != self.root || *len != self.values.len() {
+                    // every value of the peer is in the range covering both sets
+                    let start = covering.common(&self.covering());
+                    let theirs = Digest {
+                        count: *len as u64,
+                        hash: *root,
+                    };
+                    self.compare(src, [(start, theirs)], channel)?;
+                }
+            }
+            Payload::ReconcileRanges { split, digests } => {
+                let digests: HashMap<Range, Digest> =
+                    digests.iter().map(|d| (d.range, d.digest)).collect();
+                let theirs = split
+                    .iter()
+                    .filter(|range| (1..=ROOT_LEVEL).contains(&range.level))
+                    .flat_map(Range::children)
+                    .map(|range| (range, digests.get(&range).copied().unwrap_or_default()));
+                self.compare(src, theirs, channel)?;
+            }
+            Payload::ReconcileDiff { ranges, values } => {
+                let missing = self.in_ranges(ranges).difference(values);
+                self.union(values);
+                if !missing.is_empty() {
+                    channel.send(src, &Payload::ReconcileValues { values: missing })?;
+                }
+            }
+            Payload::ReconcileValues { values } => self.union(values),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that the children of `range` split it into consecutive pieces.
+    fn assert_split(range: Range) {
+        let (start, end) = range.bounds();
+        let mut next = Some(start);
+        for child in range.children() {
+            let (child_start, child_end) = child.bounds();
+            assert_eq!(Some(child_start), next, "{range:?} at {child:?}");
+            assert_eq!(Range::containing(child_start, child.level), child);
+            next = child_end.checked_add(1);
+        }
+        assert_eq!(next, end.checked_add(1), "{range:?}");
+    }
+
+    #[test]
+    fn children_split_their_range() {
+        assert_split(Range::ROOT);
+        for value in [i64::MIN, -4097, -1, 0, 1, 4096, i64::MAX] {
+            for level in 1..ROOT_LEVEL {
+                assert_split(Range::containing(value, level));
+            }
+        }
+    }
+
+    #[test]
+    fn common_range_holds_both() {
+        let single = |value| Range::containing(value, 0);
+        assert_eq!(single(3).common(&single(3)), single(3));
+        assert_eq!(single(3).common(&single(12)), Range { level: 1, index: 0 });
+        assert_eq!(single(15).common(&single(16)), Range { level: 2, index: 0 });
+        assert_eq!(single(-1).common(&single(0)), Range::ROOT);
+        assert_eq!(single(i64::MIN).common(&single(i64::MAX)), Range::ROOT);
+    }
+
+    #[test]
+    fn digests_follow_the_values() {
+        let mut a = Reconciler::new();
+        let mut b = Reconciler::new();
+        for value in 0..1000 {
+            a.insert(value);
+        }
+        for value in (0..1000).rev() {
+            b.insert(value);
+        }
+        b.insert(-5);
+        assert_eq!(a.covering(), Range { level: 3, index: 0 });
+        assert_eq!(b.covering(), Range::ROOT);
+
+        let range = Range::containing(500, 2);
+        assert_eq!(a.digest(range), b.digest(range));
+        assert_ne!(a.digest(Range::ROOT), b.digest(Range::ROOT));
+        assert_eq!(a.digest(Range::containing(-5, 1)).count, 0);
+        assert_eq!(b.digest(Range::containing(-5, 1)).count, 1);
+
+        let restored: Reconciler =
+            serde_json::from_str(&serde_json::to_string(&b).unwrap()).unwrap();
+        assert_eq!(restored.digests, b.digests);
+        assert_eq!(restored.root, b.root);
+    }
+}
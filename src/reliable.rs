@@ -0,0 +1,208 @@
+//! At-least-once delivery on top of `MessageChannel`.
+//!
+//! Messages sent through [`Reliable`] are kept until the destination replies to them, and are
+//! retransmitted with exponential backoff in the meantime. Any reply counts as an ack, so
+//! workloads keep their usual `*_ok` messages. Retransmissions reuse the original message id.
+//!
+//! Payloads go out as [`Sequenced`], numbered per destination, which lets the receiving side
+//! drop duplicates while only keeping the number up to which it delivered everything, and the
+//! numbers it delivered above that. Numbering restarts along with the sender, so every message
+//! also carries the sender's incarnation, and a newer one resets what was delivered from it.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::time;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+use crate::message;
+
+/// A payload along with its number among the reliable messages from its sender to its
+/// destination, and the incarnation of the sender that numbered it. Messages from clients have
+/// no number.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sequenced<T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub seq: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub incarnation: Option<u64>,
+    #[serde(flatten)]
+    pub payload: T,
+}
+
+/// The reliable messages delivered from one node.
+#[derive(Default)]
+struct Delivered {
+    /// The incarnation of the sender the numbers below belong to.
+    incarnation: u64,
+    /// Every number below this one was delivered.
+    watermark: u64,
+    /// Numbers delivered out of order, above the watermark.
+    above: BTreeSet<u64>,
+}
+
+impl Delivered {
+    /// Records `seq` from `incarnation`, returning whether it is new. Messages from an older
+    /// incarnation are stale retransmissions, which the restarted sender no longer waits for.
+    fn insert_from(&mut self, incarnation: u64, seq: u64) -> bool {
+        if incarnation < self.incarnation {
+            return false;
+        }
+        if incarnation > self.incarnation {
+            *self = Self {
+                incarnation,
+                ..Self::default()
+            };
+        }
+        self.insert(seq)
+    }
+
+    /// Records `seq`, returning whether it is new.
+    fn insert(&mut self, seq: u64) -> bool {
+        if seq < self.watermark || !self.above.insert(seq) {
+            return false;
+        }
+        while self.above.remove(&self.watermark) {
+            self.watermark += 1;
+        }
+        true
+    }
+}
+
+struct Pending<T> {
+    dest: String,
+    payload: Sequenced<T>,
+    timeout: time::Duration,
+    next_attempt: time::Instant,
+}
+
+pub struct Reliable<T> {
+    initial_timeout: time::Duration,
+    max_timeout: time::Duration,
+    incarnation: u64,
+
+    unacked: BTreeMap<usize, Pending<T>>,
+    next_seq: HashMap<String, u64>,
+    delivered: HashMap<String, Delivered>,
+}
+
+impl<T> Reliable<T>
+where
+    T: Serialize,
+{
+    /// Creates a layer that first retransmits after `initial_timeout`, doubling the wait after
+    /// every attempt up to `max_timeout`. The start time is the incarnation, so it grows
+    /// across restarts as long as the clock does.
+    pub fn new(initial_timeout: time::Duration, max_timeout: time::Duration) -> Self {
+        let incarnation = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        Self {
+            initial_timeout,
+            max_timeout,
+            incarnation,
+            unacked: BTreeMap::new(),
+            next_seq: HashMap::new(),
+            delivered: HashMap::new(),
+        }
+    }
+
+    /// Sends `payload` to `dest`, retransmitting it on tick until `dest` replies.
+    pub fn send(
+        &mut self,
+        channel: &mut channel::MessageChannel,
+        dest: &str,
+        payload: T,
+    ) -> Result<(), &'static str> {
+        let seq = self.next_seq.entry(dest.to_string()).or_default();
+        let payload = Sequenced {
+            seq: Some(*seq),
+            incarnation: Some(self.incarnation),
+            payload,
+        };
+        *seq += 1;
+        let msg_id = channel.next_msg_id();
+        channel.send_with_id(dest, msg_id, &payload)?;
+        let pending = Pending {
+            dest: dest.to_string(),
+            payload,
+            timeout: self.initial_timeout,
+            next_attempt: time::Instant::now() + self.initial_timeout,
+        };
+        self.unacked.insert(msg_id, pending);
+        Ok(())
+    }
+
+    /// Stops retransmitting the message `received` is a reply to. Returns whether it was
+    /// still waiting for an ack.
+    pub fn acknowledge<U>(&mut self, received: &message::Message<U>) -> bool {
+        let Some(msg_id) = received.body.in_reply_to else {
+            return false;
+        };
+        match self.unacked.get(&msg_id) {
+            Some(pending) if pending.dest == received.src => {
+                self.unacked.remove(&msg_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `received` was already delivered, recording it otherwise. Duplicates should
+    /// still be replied to, since the previous reply may have been lost. Messages without a
+    /// number, from clients, are never duplicates, and neither are the first ones from a
+    /// restarted sender.
+    pub fn is_duplicate<U>(&mut self, received: &message::Message<Sequenced<U>>) -> bool {
+        let Some(seq) = received.body.payload.seq else {
+            return false;
+        };
+        let incarnation = received.body.payload.incarnation.unwrap_or_default();
+        !self
+            .delivered
+            .entry(received.src.clone())
+            .or_default()
+            .insert_from(incarnation, seq)
+    }
+
+    /// The number of messages to `dest` that were not acknowledged yet.
+    pub fn unacked(&self, dest: &str) -> usize {
+        self.unacked.values().filter(|p| p.dest == dest).count()
+    }
+
+    /// Retransmits every message whose ack is overdue.
+    pub fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        let now = time::Instant::now();
+        for (msg_id, pending) in self.unacked.iter_mut() {
+            if pending.next_attempt > now {
+                continue;
+            }
+            channel.send_with_id(&pending.dest, *msg_id, &pending.payload)?;
+            pending.timeout = (pending.timeout * 2).min(self.max_timeout);
+            pending.next_attempt = now + pending.timeout;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarted_sender_is_not_a_duplicate() {
+        let mut delivered = Delivered::default();
+        assert!(delivered.insert_from(1, 0));
+        assert!(delivered.insert_from(1, 1));
+        assert!(!delivered.insert_from(1, 0));
+
+        assert!(delivered.insert_from(2, 0));
+        assert!(!delivered.insert_from(2, 0));
+        assert!(!delivered.insert_from(1, 2));
+        assert!(delivered.insert_from(2, 1));
+    }
+}
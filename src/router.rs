@@ -0,0 +1,262 @@
+//! Dispatching messages by their `type` instead of matching on one payload enum.
+//!
+//! A [`Router`] owns the state of a node and a handler per message type, each taking its own
+//! payload struct. Requests of a type nobody registered get a `not-supported` error, while
+//! replies of such a type are dropped: answering them could start an endless exchange of
+//! errors.
+//!
+//! Behaviors shared between workloads, like [`topology`] and [`read`], are functions that
+//! register their handlers on a router, and are added with [`Router::with`].
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! #[serde(tag = "type", rename = "echo")]
+//! struct Echo {
+//!     echo: String,
+//! }
+//!
+//! let mut router = router::Router::new(()).on("echo", |_, received: &Message<Echo>, channel| {
+//!     let echo = received.body.payload.echo.clone();
+//!     channel.reply(received, &EchoOk { echo })
+//! });
+//! chidori::main_loop(&mut router)
+//! ```
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::channel;
+use crate::message;
+use crate::topology;
+use crate::Event;
+
+pub const NOT_SUPPORTED: u64 = 10;
+pub const MALFORMED_REQUEST: u64 = 12;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename = "error")]
+pub struct Error {
+    pub code: u64,
+    #[serde(default)]
+    pub text: String,
+}
+
+type Route<S> =
+    Box<dyn FnMut(&mut S, &message::Message<Value>, &mut channel::MessageChannel) -> RouteResult>;
+
+type Tick<S> = Box<dyn FnMut(&mut S, &mut channel::MessageChannel) -> Result<(), &'static str>>;
+
+enum RouteResult {
+    Handled(Result<(), &'static str>),
+    Malformed,
+}
+
+pub struct Router<S> {
+    state: S,
+    routes: HashMap<String, Route<S>>,
+    tick: Option<(time::Duration, Tick<S>)>,
+}
+
+impl<S> Router<S>
+where
+    S: 'static,
+{
+    pub fn new(state: S) -> Self {
+        Self {
+            state,
+            routes: HashMap::new(),
+            tick: None,
+        }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut S {
+        &mut self.state
+    }
+
+    /// Handles messages of `message_type` with `handler`, replacing any handler registered for
+    /// it before. Payloads that do not deserialize into `P` get a `malformed-request` error.
+    pub fn on<P, F>(mut self, message_type: &str, mut handler: F) -> Self
+    where
+        P: DeserializeOwned,
+        F: FnMut(
+                &mut S,
+                &message::Message<P>,
+                &mut channel::MessageChannel,
+            ) -> Result<(), &'static str>
+            + 'static,
+    {
+        let route = move |state: &mut S,
+                          received: &message::Message<Value>,
+                          channel: &mut channel::MessageChannel| {
+            let Ok(payload) = P::deserialize(&received.body.payload) else {
+                return RouteResult::Malformed;
+            };
+            let typed = message::Message {
+                src: received.src.clone(),
+                dest: received.dest.clone(),
+                body: message::MessageBody {
+                    msg_id: received.body.msg_id,
+                    in_reply_to: received.body.in_reply_to,
+                    stamp: received.body.stamp.clone(),
+                    payload,
+                },
+            };
+            RouteResult::Handled(handler(state, &typed, channel))
+        };
+        self.routes
+            .insert(message_type.to_string(), Box::new(route));
+        self
+    }
+
+    /// Calls `handler` every `interval`.
+    pub fn on_tick<F>(mut self, interval: time::Duration, handler: F) -> Self
+    where
+        F: FnMut(&mut S, &mut channel::MessageChannel) -> Result<(), &'static str> + 'static,
+    {
+        self.tick = Some((interval, Box::new(handler)));
+        self
+    }
+
+    /// Adds a behavior, which registers handlers of its own.
+    pub fn with<F>(self, behavior: F) -> Self
+    where
+        F: FnOnce(Self) -> Self,
+    {
+        behavior(self)
+    }
+}
+
+impl<S> crate::Handler<Value> for Router<S> {
+    fn handle_message(
+        &mut self,
+        received: &message::Message<Value>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let message_type = received
+            .body
+            .payload
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let result = match self.routes.get_mut(message_type) {
+            Some(route) => route(&mut self.state, received, channel),
+            None if received.body.in_reply_to.is_some() => return Ok(()),
+            None => {
+                let error = Error {
+                    code: NOT_SUPPORTED,
+                    text: format!("{message_type} is not supported"),
+                };
+                return channel.reply(received, &error);
+            }
+        };
+        match result {
+            RouteResult::Handled(result) => result,
+            RouteResult::Malformed if received.body.in_reply_to.is_some() => Ok(()),
+            RouteResult::Malformed => {
+                let error = Error {
+                    code: MALFORMED_REQUEST,
+                    text: format!("malformed {message_type}"),
+                };
+                channel.reply(received, &error)
+            }
+        }
+    }
+
+    fn handle_tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        match &mut self.tick {
+            Some((_, tick)) => tick(&mut self.state, channel),
+            None => Ok(()),
+        }
+    }
+
+    fn send_events(&self, send_channel: &mpsc::Sender<Event>) {
+        let Some((interval, _)) = self.tick else {
+            return;
+        };
+        let send_channel = send_channel.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            send_channel.send(Event::Tick).unwrap();
+        });
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename = "topology")]
+pub struct Topology {
+    pub topology: topology::Topology,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename = "topology_ok")]
+pub struct TopologyOk {}
+
+/// Keeps the neighbors of this node in the field returned by `neighbors`, computed by
+/// `strategy` if there is one and taken from the suggested topology otherwise.
+pub fn topology<S, F>(
+    strategy: Option<topology::Strategy>,
+    mut neighbors: F,
+) -> impl FnOnce(Router<S>) -> Router<S>
+where
+    S: 'static,
+    F: FnMut(&mut S) -> &mut Option<Vec<String>> + 'static,
+{
+    move |router| {
+        router.on(
+            "topology",
+            move |state, received: &message::Message<Topology>, channel| {
+                let suggested = &received.body.payload.topology;
+                // an overlay computed locally takes precedence over the suggested topology
+                let topology = match &strategy {
+                    Some(strategy) => strategy.compute(&channel.node_ids, suggested),
+                    None => suggested.clone(),
+                };
+                *neighbors(state) = topology.get(&channel.node_id).cloned();
+                channel.reply(received, &TopologyOk {})
+            },
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename = "read")]
+pub struct Read {}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<'a, V: ?Sized> {
+    messages: &'a V,
+}
+
+/// Answers broadcast reads with the messages `messages` borrows from the state.
+pub fn read<S, F, V>(messages: F) -> impl FnOnce(Router<S>) -> Router<S>
+where
+    S: 'static,
+    F: for<'a> Fn(&'a S) -> &'a V + 'static,
+    V: Serialize + ?Sized,
+{
+    move |router| {
+        router.on(
+            "read",
+            move |state, received: &message::Message<Read>, channel| {
+                channel.reply(
+                    received,
+                    &ReadOk {
+                        messages: messages(state),
+                    },
+                )
+            },
+        )
+    }
+}
@@ -0,0 +1,113 @@
+//! Rumor mongering with feedback and probabilistic cooling.
+//!
+//! A value is hot from the moment a node learns it, and hot values are pushed to a few random
+//! peers every round. Peers answer with the values they already knew, and each of those cools
+//! with some probability, after which it is no longer pushed. A higher probability stops
+//! rumors sooner and sends fewer messages, at the cost of more nodes left for anti-entropy to
+//! reach.
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+use crate::intset::IntSet;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    RumorPush { values: IntSet },
+    RumorFeedback { known: IntSet },
+}
+
+pub struct Rumors {
+    fanout: usize,
+    cooling: f64,
+
+    hot: IntSet,
+}
+
+impl Rumors {
+    /// Creates rumors pushed to `fanout` peers per round, each cooling with probability
+    /// `cooling` whenever a peer reports it already knew it. `cooling` is clamped to [0, 1],
+    /// and NaN taken as 0.
+    pub fn new(fanout: usize, cooling: f64) -> Self {
+        Self {
+            fanout,
+            cooling: if cooling.is_nan() {
+                0.0
+            } else {
+                cooling.clamp(0.0, 1.0)
+            },
+            hot: IntSet::new(),
+        }
+    }
+
+    pub fn hot(&self) -> &IntSet {
+        &self.hot
+    }
+
+    /// Starts spreading a value this node just learned.
+    pub fn spread(&mut self, value: i64) {
+        self.hot.insert(value);
+    }
+
+    /// Handles a rumor message. `learn` adds a value to the set being disseminated, returning
+    /// whether it was new.
+    pub fn handle<F>(
+        &mut self,
+        src: &str,
+        payload: &Payload,
+        channel: &mut channel::MessageChannel,
+        mut learn: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnMut(i64) -> bool,
+    {
+        match payload {
+            Payload::RumorPush { values } => {
+                let mut known = IntSet::new();
+                for value in values.iter() {
+                    if learn(value) {
+                        self.hot.insert(value);
+                    } else {
+                        known.insert(value);
+                    }
+                }
+                if !known.is_empty() {
+                    channel.send(src, &Payload::RumorFeedback { known })?;
+                }
+            }
+            Payload::RumorFeedback { known } => {
+                let mut rng = rand::thread_rng();
+                let cooled: IntSet = known
+                    .iter()
+                    .filter(|v| self.hot.contains(*v) && rng.gen_bool(self.cooling))
+                    .collect();
+                self.hot = self.hot.difference(&cooled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes the hot values to random peers.
+    pub fn tick(
+        &mut self,
+        peers: &[String],
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        if self.hot.is_empty() {
+            return Ok(());
+        }
+        let mut rng = rand::thread_rng();
+        let push = Payload::RumorPush {
+            values: self.hot.clone(),
+        };
+        for peer in peers.choose_multiple(&mut rng, self.fanout) {
+            channel.send(peer, &push)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,559 @@
+//! State that survives restarts.
+//!
+//! A [`Storage`] keeps a snapshot of some state along with a write-ahead log of the records
+//! applied to it since. Recovering means loading both and replaying the records on top of the
+//! snapshot. Taking a snapshot now and then keeps the log, and recovery, short.
+//!
+//! [`FileStorage`] writes the log as JSON lines and the snapshot as a JSON file, both in one
+//! directory. Every record carries an index, and the snapshot the index of the last record it
+//! covers, so a crash between writing a snapshot and truncating the log replays nothing twice.
+//! A crash in the middle of an append leaves a torn last line, which is dropped.
+//!
+//! [`Persistent`] puts the two together for state that changes by applying records: it logs
+//! every record before applying it, and takes a snapshot whenever the log grew long enough.
+//!
+//! State that is small enough to be written whole every time can do without a log, using
+//! [`save`] and [`restore`].
+
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path;
+use std::time;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::twopc;
+
+const LOG_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+pub trait Storage<S, R> {
+    /// Appends a record to the log. It is durable once the sync policy says so.
+    fn append(&mut self, record: &R) -> Result<(), &'static str>;
+
+    /// Makes every appended record durable.
+    fn sync(&mut self) -> Result<(), &'static str>;
+
+    /// Syncs if the sync policy says it is time, to be called regularly.
+    fn tick(&mut self) -> Result<(), &'static str>;
+
+    /// Replaces the log with a snapshot of the state it led to.
+    fn snapshot(&mut self, state: &S) -> Result<(), &'static str>;
+
+    /// The latest snapshot and the records appended after it.
+    fn load(&self) -> Result<(Option<S>, Vec<R>), &'static str>;
+
+    /// The number of records appended since the latest snapshot.
+    fn log_len(&self) -> usize;
+}
+
+pub struct MemoryStorage<S, R> {
+    snapshot: Option<S>,
+    records: Vec<R>,
+}
+
+impl<S, R> MemoryStorage<S, R> {
+    pub fn new() -> Self {
+        Self {
+            snapshot: None,
+            records: Vec::new(),
+        }
+    }
+}
+
+impl<S, R> Default for MemoryStorage<S, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, R> Storage<S, R> for MemoryStorage<S, R>
+where
+    S: Clone,
+    R: Clone,
+{
+    fn append(&mut self, record: &R) -> Result<(), &'static str> {
+        self.records.push(record.clone());
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn snapshot(&mut self, state: &S) -> Result<(), &'static str> {
+        self.snapshot = Some(state.clone());
+        self.records.clear();
+        Ok(())
+    }
+
+    fn load(&self) -> Result<(Option<S>, Vec<R>), &'static str> {
+        Ok((self.snapshot.clone(), self.records.clone()))
+    }
+
+    fn log_len(&self) -> usize {
+        self.records.len()
+    }
+}
+
+/// When appends to a [`FileStorage`] are synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// After every append, so that `append` returning means the record is durable.
+    Always,
+    /// On the first append or tick after the interval elapsed. As long as ticks come at least
+    /// that often, a crash loses at most about two intervals of appends.
+    Interval(time::Duration),
+    /// Only on `sync` and snapshots, leaving the rest to the operating system.
+    Never,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Indexed<T> {
+    index: u64,
+    #[serde(flatten)]
+    value: T,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Wrapped<T> {
+    record: T,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotState<T> {
+    state: T,
+}
+
+pub struct FileStorage<S, R> {
+    dir: path::PathBuf,
+    policy: SyncPolicy,
+
+    log: fs::File,
+    next_index: u64,
+    log_len: usize,
+    last_sync: time::Instant,
+    /// Whether records were appended since the last sync.
+    unsynced: bool,
+
+    _types: PhantomData<(S, R)>,
+}
+
+impl<S, R> FileStorage<S, R>
+where
+    S: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+{
+    /// Opens the storage in `dir`, creating it if needed.
+    pub fn open(dir: &path::Path, policy: SyncPolicy) -> Result<Self, &'static str> {
+        fs::create_dir_all(dir).map_err(|_| "cannot create storage directory")?;
+        let log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(dir.join(LOG_FILE))
+            .map_err(|_| "cannot open log")?;
+        let mut storage = Self {
+            dir: dir.to_path_buf(),
+            policy,
+            log,
+            next_index: 0,
+            log_len: 0,
+            last_sync: time::Instant::now(),
+            unsynced: false,
+            _types: PhantomData,
+        };
+
+        let (snapshot_index, records, valid_len) = storage.read()?;
+        // drop a torn append, later appends would be glued to it
+        storage
+            .log
+            .set_len(valid_len)
+            .map_err(|_| "cannot truncate log")?;
+        let last_logged = records.last().map(|r| r.index + 1);
+        storage.next_index = last_logged.unwrap_or(0).max(snapshot_index.unwrap_or(0));
+        storage.log_len = records.len();
+        Ok(storage)
+    }
+
+    /// Reads the index after the snapshot, the records after it, and the length of the log
+    /// up to its last complete record.
+    #[allow(clippy::type_complexity)]
+    fn read(&self) -> Result<(Option<u64>, Vec<Indexed<R>>, u64), &'static str> {
+        let snapshot = self.read_snapshot()?;
+        let covered = snapshot.as_ref().map(|s| s.index);
+
+        let file = fs::File::open(self.dir.join(LOG_FILE)).map_err(|_| "cannot read log")?;
+        let mut reader = io::BufReader::new(file);
+        let mut records = Vec::new();
+        let mut valid_len = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(|_| "cannot read log")?;
+            if read == 0 {
+                break;
+            }
+            let Ok(record) = serde_json::from_str::<Indexed<Wrapped<R>>>(&line) else {
+                // only the last line may be torn
+                if reader.fill_buf().map_or(true, |rest| rest.is_empty()) {
+                    break;
+                }
+                return Err("corrupt log");
+            };
+            if !line.ends_with('\n') {
+                break;
+            }
+            valid_len += read as u64;
+            if covered.is_none_or(|covered| record.index >= covered) {
+                records.push(Indexed {
+                    index: record.index,
+                    value: record.value.record,
+                });
+            }
+        }
+        Ok((covered, records, valid_len))
+    }
+
+    fn read_snapshot(&self) -> Result<Option<Indexed<SnapshotState<S>>>, &'static str> {
+        match fs::read_to_string(self.dir.join(SNAPSHOT_FILE)) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|_| "corrupt snapshot"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(_) => Err("cannot read snapshot"),
+        }
+    }
+}
+
+impl<S, R> Storage<S, R> for FileStorage<S, R>
+where
+    S: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+{
+    fn append(&mut self, record: &R) -> Result<(), &'static str> {
+        let indexed = Indexed {
+            index: self.next_index,
+            value: Wrapped { record },
+        };
+        let mut line = serde_json::to_vec(&indexed).map_err(|_| "unserializable record")?;
+        line.push(b'\n');
+        self.log.write_all(&line).map_err(|_| "cannot append")?;
+        self.next_index += 1;
+        self.log_len += 1;
+        self.unsynced = true;
+
+        if self.policy == SyncPolicy::Always {
+            return self.sync();
+        }
+        self.tick()
+    }
+
+    fn sync(&mut self) -> Result<(), &'static str> {
+        self.log.sync_data().map_err(|_| "cannot sync log")?;
+        self.last_sync = time::Instant::now();
+        self.unsynced = false;
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<(), &'static str> {
+        match self.policy {
+            SyncPolicy::Interval(interval)
+                if self.unsynced && self.last_sync.elapsed() >= interval =>
+            {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn snapshot(&mut self, state: &S) -> Result<(), &'static str> {
+        let snapshot = Indexed {
+            index: self.next_index,
+            value: SnapshotState { state },
+        };
+        let contents = serde_json::to_string(&snapshot).map_err(|_| "unserializable state")?;
+        write_synced(&self.dir.join(SNAPSHOT_FILE), &contents)
+            .map_err(|_| "cannot write snapshot")?;
+        // the records are covered by the snapshot now, even if truncating fails
+        self.log.set_len(0).map_err(|_| "cannot truncate log")?;
+        self.sync()?;
+        self.log_len = 0;
+        Ok(())
+    }
+
+    fn load(&self) -> Result<(Option<S>, Vec<R>), &'static str> {
+        let snapshot = self.read_snapshot()?.map(|s| s.value.state);
+        let (_, records, _) = self.read()?;
+        Ok((snapshot, records.into_iter().map(|r| r.value).collect()))
+    }
+
+    fn log_len(&self) -> usize {
+        self.log_len
+    }
+}
+
+/// State that changes by applying records to it.
+pub trait Apply<R> {
+    fn apply(&mut self, record: &R);
+}
+
+/// State kept in a [`Storage`]: records are logged before they are applied, and a snapshot
+/// replaces the log every `snapshot_every` records.
+pub struct Persistent<S, R, T> {
+    state: S,
+    storage: T,
+    snapshot_every: usize,
+    _records: PhantomData<R>,
+}
+
+impl<S, R, T> Persistent<S, R, T>
+where
+    S: Apply<R> + Default,
+    T: Storage<S, R>,
+{
+    /// Rebuilds the state from the latest snapshot in `storage` and the records after it.
+    pub fn recover(storage: T, snapshot_every: usize) -> Result<Self, &'static str> {
+        let (snapshot, records) = storage.load()?;
+        let mut state = snapshot.unwrap_or_default();
+        for record in &records {
+            state.apply(record);
+        }
+        Ok(Self {
+            state,
+            storage,
+            snapshot_every: snapshot_every.max(1),
+            _records: PhantomData,
+        })
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Logs `record`, then applies it.
+    pub fn apply(&mut self, record: R) -> Result<(), &'static str> {
+        self.storage.append(&record)?;
+        self.state.apply(&record);
+        if self.storage.log_len() >= self.snapshot_every {
+            self.storage.snapshot(&self.state)?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the log when the sync policy says so.
+    pub fn tick(&mut self) -> Result<(), &'static str> {
+        self.storage.tick()
+    }
+
+    /// Simulates a crash, keeping only what was written to the storage.
+    pub fn into_storage(self) -> T {
+        self.storage
+    }
+}
+
+/// Two-phase commit records kept in a file, so coordinators and participants can `recover`
+/// after a restart. Every append is synced, decisions must be durable before acting on them.
+/// The records are read once when opening, and kept in memory from then on.
+pub struct FileDecisionLog<T> {
+    storage: FileStorage<(), twopc::Record<T>>,
+    records: Vec<twopc::Record<T>>,
+}
+
+impl<T> FileDecisionLog<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    pub fn open(dir: &path::Path) -> Result<Self, &'static str> {
+        // `append` syncs itself, whatever the policy
+        let storage = FileStorage::open(dir, SyncPolicy::Never)?;
+        let (_, records) = storage.load()?;
+        Ok(Self { storage, records })
+    }
+}
+
+impl<T> twopc::DecisionLog<T> for FileDecisionLog<T>
+where
+    T: Serialize + DeserializeOwned + Clone,
+{
+    fn append(&mut self, record: twopc::Record<T>) -> Result<(), &'static str> {
+        self.storage.append(&record)?;
+        self.storage.sync()?;
+        self.records.push(record);
+        Ok(())
+    }
+
+    fn records(&self) -> Vec<twopc::Record<T>> {
+        self.records.clone()
+    }
+}
+
+/// Writes a snapshot of `state` to `path`, replacing the previous one.
+pub fn save<T>(path: &path::Path, state: &T) -> Result<(), &'static str>
+where
+    T: Serialize,
+{
+    let contents = serde_json::to_string(state).map_err(|_| "unserializable state")?;
+    write_synced(path, &contents).map_err(|_| "cannot write snapshot")
+}
+
+/// Reads the snapshot written to `path` by `save`, if there is one.
+pub fn restore<T>(path: &path::Path) -> Result<Option<T>, &'static str>
+where
+    T: DeserializeOwned,
+{
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|_| "corrupt snapshot"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(_) => Err("cannot read snapshot"),
+    }
+}
+
+/// Replaces the file at `path` with `contents`, durably: either the old or the new contents
+/// survive a crash.
+pub(crate) fn write_synced(path: &path::Path, contents: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    // make the rename itself durable
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("chidori-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &path::Path) -> FileStorage<Vec<u64>, u64> {
+        FileStorage::open(dir, SyncPolicy::Always).unwrap()
+    }
+
+    #[derive(Default, Clone)]
+    struct Sum(u64);
+
+    impl Apply<u64> for Sum {
+        fn apply(&mut self, record: &u64) {
+            self.0 += record;
+        }
+    }
+
+    #[test]
+    fn torn_last_line_is_dropped() {
+        let dir = dir("torn");
+        let mut storage = open(&dir);
+        for record in 0..3 {
+            storage.append(&record).unwrap();
+        }
+        drop(storage);
+        let mut log = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        log.write_all(br#"{"index":3,"rec"#).unwrap();
+
+        let mut storage = open(&dir);
+        assert_eq!(storage.load().unwrap(), (None, vec![0, 1, 2]));
+        storage.append(&3).unwrap();
+        drop(storage);
+        assert_eq!(open(&dir).load().unwrap(), (None, vec![0, 1, 2, 3]));
+
+        // anything but the last line is corruption, not a torn append
+        let mut log = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        log.write_all(b"{}\n{\"index\":5,\"record\":5}\n").unwrap();
+        assert!(FileStorage::<Vec<u64>, u64>::open(&dir, SyncPolicy::Never).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_truncates_the_log() {
+        let dir = dir("truncate");
+        let mut storage = open(&dir);
+        storage.append(&1).unwrap();
+        storage.append(&2).unwrap();
+        storage.snapshot(&vec![1, 2]).unwrap();
+        assert_eq!(storage.log_len(), 0);
+        assert_eq!(fs::metadata(dir.join(LOG_FILE)).unwrap().len(), 0);
+
+        storage.append(&3).unwrap();
+        drop(storage);
+        let mut storage = open(&dir);
+        assert_eq!(storage.log_len(), 1);
+        assert_eq!(storage.load().unwrap(), (Some(vec![1, 2]), vec![3]));
+        storage.append(&4).unwrap();
+        assert_eq!(storage.load().unwrap(), (Some(vec![1, 2]), vec![3, 4]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_starts_after_the_snapshot() {
+        let dir = dir("replay");
+        let mut storage = open(&dir);
+        storage.append(&1).unwrap();
+        storage.append(&2).unwrap();
+        let before = fs::read(dir.join(LOG_FILE)).unwrap();
+        storage.snapshot(&vec![1, 2]).unwrap();
+        storage.append(&3).unwrap();
+        drop(storage);
+
+        // a crash after writing the snapshot, before truncating the log
+        let after = fs::read(dir.join(LOG_FILE)).unwrap();
+        fs::write(dir.join(LOG_FILE), [before, after].concat()).unwrap();
+        let storage = open(&dir);
+        assert_eq!(storage.load().unwrap(), (Some(vec![1, 2]), vec![3]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interval_syncs_on_tick() {
+        let dir = dir("interval");
+        let policy = SyncPolicy::Interval(time::Duration::ZERO);
+        let mut storage = FileStorage::<Vec<u64>, u64>::open(&dir, policy).unwrap();
+        storage.append(&1).unwrap();
+        assert!(!storage.unsynced);
+        storage.policy = SyncPolicy::Interval(time::Duration::from_secs(3600));
+        storage.append(&2).unwrap();
+        assert!(storage.unsynced);
+        storage.policy = SyncPolicy::Interval(time::Duration::ZERO);
+        storage.tick().unwrap();
+        assert!(!storage.unsynced);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn persistent_state_snapshots_periodically() {
+        let mut persistent = Persistent::<Sum, u64, _>::recover(MemoryStorage::new(), 3).unwrap();
+        for record in 1..=7 {
+            persistent.apply(record).unwrap();
+        }
+        assert_eq!(persistent.state().0, 28);
+        let storage = persistent.into_storage();
+        assert_eq!(storage.log_len(), 1);
+
+        let persistent = Persistent::<Sum, u64, _>::recover(storage, 3).unwrap();
+        assert_eq!(persistent.state().0, 28);
+    }
+}
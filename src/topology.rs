@@ -0,0 +1,226 @@
+//! Broadcast overlays computed locally from the node ids.
+//!
+//! Every node computes the same overlay from the sorted node ids, so no coordination is needed
+//! to agree on it. The strategy is picked with the `CHIDORI_TOPOLOGY` environment variable:
+//!
+//! - `spanning-tree`: a minimum-depth spanning tree of the topology sent by Maelstrom
+//! - `tree:K`: a K-ary tree
+//! - `hub:H`: H fully connected hubs, each connected to every other node
+//! - `small-world:K:P`: a Watts-Strogatz graph where each node has K ring neighbors and each
+//!   edge is rewired with probability P
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::env;
+use std::str::FromStr;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+pub const ENV_VAR: &str = "CHIDORI_TOPOLOGY";
+
+/// Seed shared by every node so that random overlays come out the same everywhere.
+const SMALL_WORLD_SEED: u64 = 0x0063_6869_646f_7269;
+
+pub type Topology = HashMap<String, Vec<String>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Strategy {
+    SpanningTree,
+    KaryTree { k: usize },
+    HubAndSpoke { hubs: usize },
+    SmallWorld { degree: usize, rewire: f64 },
+}
+
+impl FromStr for Strategy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let kind = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+        let strategy = match (kind, args.as_slice()) {
+            ("spanning-tree", []) => Strategy::SpanningTree,
+            ("tree", [k]) => Strategy::KaryTree {
+                k: k.parse().map_err(|_| "invalid tree arity")?,
+            },
+            ("hub", [hubs]) => Strategy::HubAndSpoke {
+                hubs: hubs.parse().map_err(|_| "invalid number of hubs")?,
+            },
+            ("small-world", [degree, rewire]) => Strategy::SmallWorld {
+                degree: degree.parse().map_err(|_| "invalid small-world degree")?,
+                rewire: rewire
+                    .parse()
+                    .ok()
+                    .filter(|p| (0.0..=1.0).contains(p))
+                    .ok_or("invalid small-world rewiring probability")?,
+            },
+            _ => return Err("unknown topology strategy"),
+        };
+        Ok(strategy)
+    }
+}
+
+impl Strategy {
+    /// Reads the strategy from `CHIDORI_TOPOLOGY`, if set.
+    pub fn from_env() -> Result<Option<Self>, &'static str> {
+        match env::var(ENV_VAR) {
+            Ok(value) => value.parse().map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Computes the neighbors of every node. `base` is the topology suggested by Maelstrom,
+    /// which only the spanning tree makes use of.
+    pub fn compute(&self, node_ids: &[String], base: &Topology) -> Topology {
+        let mut nodes = node_ids.to_vec();
+        nodes.sort();
+
+        let edges = match *self {
+            Strategy::SpanningTree => spanning_tree(&nodes, base),
+            Strategy::KaryTree { k } => kary_tree(&nodes, k.max(1)),
+            Strategy::HubAndSpoke { hubs } => hub_and_spoke(&nodes, hubs.max(1)),
+            Strategy::SmallWorld { degree, rewire } => small_world(&nodes, degree, rewire),
+        };
+
+        let mut topology: HashMap<String, BTreeSet<String>> = nodes
+            .iter()
+            .map(|node| (node.clone(), BTreeSet::new()))
+            .collect();
+        for (a, b) in edges {
+            if a != b {
+                topology
+                    .get_mut(&nodes[a])
+                    .unwrap()
+                    .insert(nodes[b].clone());
+                topology
+                    .get_mut(&nodes[b])
+                    .unwrap()
+                    .insert(nodes[a].clone());
+            }
+        }
+        topology
+            .into_iter()
+            .map(|(node, neighbors)| (node, neighbors.into_iter().collect()))
+            .collect()
+    }
+}
+
+/// Breadth-first distances from `start`, `None` for unreachable nodes.
+fn distances(adjacency: &[Vec<usize>], start: usize) -> Vec<Option<usize>> {
+    let mut distances = vec![None; adjacency.len()];
+    distances[start] = Some(0);
+    let mut queue = VecDeque::from([start]);
+    while let Some(node) = queue.pop_front() {
+        for &neighbor in &adjacency[node] {
+            if distances[neighbor].is_none() {
+                distances[neighbor] = Some(distances[node].unwrap() + 1);
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    distances
+}
+
+/// A breadth-first tree rooted at the center of `base`, the node whose farthest node is
+/// closest, which minimizes the depth of the tree.
+fn spanning_tree(nodes: &[String], base: &Topology) -> Vec<(usize, usize)> {
+    if nodes.is_empty() {
+        return Vec::new();
+    }
+    let index: HashMap<&String, usize> = nodes.iter().enumerate().map(|(i, n)| (n, i)).collect();
+    let mut adjacency = vec![Vec::new(); nodes.len()];
+    for (node, neighbors) in base {
+        let Some(&a) = index.get(node) else {
+            continue;
+        };
+        for neighbor in neighbors {
+            if let Some(&b) = index.get(neighbor) {
+                adjacency[a].push(b);
+                adjacency[b].push(a);
+            }
+        }
+    }
+    for neighbors in adjacency.iter_mut() {
+        neighbors.sort();
+        neighbors.dedup();
+    }
+
+    // unreachable nodes count as infinitely far, so disconnected roots lose
+    let eccentricity = |root: usize| {
+        distances(&adjacency, root)
+            .iter()
+            .map(|d| d.unwrap_or(usize::MAX))
+            .max()
+            .unwrap()
+    };
+    let root = (0..nodes.len()).min_by_key(|&n| eccentricity(n)).unwrap();
+
+    let mut edges = Vec::new();
+    let mut visited = vec![false; nodes.len()];
+    visited[root] = true;
+    let mut queue = VecDeque::from([root]);
+    while let Some(node) = queue.pop_front() {
+        for &neighbor in &adjacency[node] {
+            if !visited[neighbor] {
+                visited[neighbor] = true;
+                edges.push((node, neighbor));
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    // nodes the base topology does not reach hang off the root directly
+    for (node, visited) in visited.iter().enumerate() {
+        if !visited {
+            edges.push((root, node));
+        }
+    }
+    edges
+}
+
+fn kary_tree(nodes: &[String], k: usize) -> Vec<(usize, usize)> {
+    (1..nodes.len())
+        .map(|child| ((child - 1) / k, child))
+        .collect()
+}
+
+fn hub_and_spoke(nodes: &[String], hubs: usize) -> Vec<(usize, usize)> {
+    let hubs = hubs.min(nodes.len());
+    let mut edges = Vec::new();
+    for hub in 0..hubs {
+        // hubs talk to each other and to every spoke, so any single hub can fail
+        for other in hub + 1..nodes.len() {
+            edges.push((hub, other));
+        }
+    }
+    edges
+}
+
+fn small_world(nodes: &[String], degree: usize, rewire: f64) -> Vec<(usize, usize)> {
+    let n = nodes.len();
+    if n < 2 {
+        return Vec::new();
+    }
+    let mut rng = StdRng::seed_from_u64(SMALL_WORLD_SEED);
+    let mut edges = BTreeSet::new();
+    for node in 0..n {
+        for offset in 1..=(degree / 2).max(1) {
+            let mut neighbor = (node + offset) % n;
+            // NaN, which `from_str` rejects, never rewires
+            if rewire > 0.0 && rng.gen_bool(rewire.min(1.0)) {
+                neighbor = rng.gen_range(0..n);
+            }
+            if neighbor != node {
+                edges.insert((node.min(neighbor), node.max(neighbor)));
+            }
+        }
+    }
+    // keep the ring so the graph stays connected regardless of rewiring
+    for node in 0..n {
+        let neighbor = (node + 1) % n;
+        edges.insert((node.min(neighbor), node.max(neighbor)));
+    }
+    edges.into_iter().collect()
+}
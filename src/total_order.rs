@@ -0,0 +1,686 @@
+//! Total-order broadcast.
+//!
+//! Every node delivers the same values in the same order, so applying them in delivery order
+//! replicates a state machine. Two backends implement [`TotalOrder`]:
+//!
+//! - [`Sequencer`] sends values to a single node that numbers them. The sequencer is either
+//!   fixed, which stalls while it is down, or elected, in which case a new sequencer first
+//!   recovers from a majority what the previous one may have committed, as in Paxos. Slots
+//!   are committed once a majority has them, and slots nobody knows about after a failover are
+//!   filled with no-ops.
+//! - [`Consensus`] claims the slots of a log kept in Maelstrom's `lin-kv` service, each with a
+//!   compare-and-set that only succeeds on a missing key. Slots are thus written once and
+//!   agreed on by every node without any coordination between them.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::time;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+use crate::election;
+use crate::kv;
+
+/// The number of slots sent in a single append.
+const MAX_APPEND: usize = 256;
+
+pub trait TotalOrder<T> {
+    /// Submits a value to be delivered by every node.
+    fn broadcast(
+        &mut self,
+        value: T,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str>;
+
+    fn handle(
+        &mut self,
+        src: &str,
+        in_reply_to: Option<usize>,
+        payload: &Payload<T>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str>;
+
+    fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str>;
+
+    /// Takes the values delivered since the last call, in delivery order.
+    fn take_delivered(&mut self) -> Vec<T>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EntryId {
+    pub node: String,
+    pub seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry<T> {
+    pub id: EntryId,
+    pub value: T,
+}
+
+/// A slot of the sequencer log along with the term of the sequencer that wrote it. Slots
+/// without an entry are no-ops.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Slot<T> {
+    pub term: u64,
+    pub entry: Option<Entry<T>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum OrderPayload<T> {
+    OrderSubmit {
+        entry: Entry<T>,
+    },
+    OrderAppend {
+        term: u64,
+        from: u64,
+        slots: Vec<Slot<T>>,
+        commit: u64,
+    },
+    /// The number of leading slots the sender holds for `term`.
+    OrderAck {
+        term: u64,
+        len: u64,
+    },
+    OrderRecover {
+        term: u64,
+        from: u64,
+    },
+    OrderLog {
+        term: u64,
+        slots: Vec<Slot<T>>,
+    },
+}
+
+/// Everything the backends send or receive, including the messages of the leader election
+/// and of the key-value service.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Payload<T> {
+    Order(OrderPayload<T>),
+    Election(election::Payload),
+    Kv(kv::Payload),
+}
+
+fn majority(channel: &channel::MessageChannel) -> usize {
+    channel.node_ids.len() / 2 + 1
+}
+
+fn peers(channel: &channel::MessageChannel) -> Vec<String> {
+    channel
+        .node_ids
+        .iter()
+        .filter(|n| **n != channel.node_id)
+        .cloned()
+        .collect()
+}
+
+enum Mode {
+    Fixed(String),
+    Elected(Box<election::Election>),
+}
+
+struct Recovery<T> {
+    term: u64,
+    from: u64,
+    logs: HashMap<String, Vec<Slot<T>>>,
+}
+
+pub struct Sequencer<T> {
+    mode: Mode,
+    resubmit_interval: time::Duration,
+
+    /// Submitted values that were not delivered yet.
+    pending: BTreeMap<u64, T>,
+    next_seq: u64,
+    last_submitted: time::Instant,
+
+    /// The highest term this node accepted slots or promised recovery for.
+    promised: u64,
+    log: Vec<Slot<T>>,
+    /// The number of leading slots held for the promised term, or already delivered.
+    accepted: u64,
+    commit: u64,
+    delivered: u64,
+    values: Vec<T>,
+
+    // sequencer state
+    recovered: Option<u64>,
+    recovery: Option<Recovery<T>>,
+    ids: HashSet<EntryId>,
+    next: HashMap<String, u64>,
+    matched: HashMap<String, u64>,
+}
+
+impl<T> Sequencer<T>
+where
+    T: Clone + Serialize,
+{
+    /// Creates a sequencer backend where `sequencer` numbers every value.
+    pub fn fixed(sequencer: &str, resubmit_interval: time::Duration) -> Self {
+        let mut this = Self::new(Mode::Fixed(sequencer.to_string()), resubmit_interval);
+        this.recovered = Some(0);
+        this
+    }
+
+    /// Creates a sequencer backend where the sequencer is the leader chosen by `election`.
+    pub fn elected(election: election::Election, resubmit_interval: time::Duration) -> Self {
+        Self::new(Mode::Elected(Box::new(election)), resubmit_interval)
+    }
+
+    fn new(mode: Mode, resubmit_interval: time::Duration) -> Self {
+        Self {
+            mode,
+            resubmit_interval,
+            pending: BTreeMap::new(),
+            next_seq: 0,
+            last_submitted: time::Instant::now(),
+            promised: 0,
+            log: Vec::new(),
+            accepted: 0,
+            commit: 0,
+            delivered: 0,
+            values: Vec::new(),
+            recovered: None,
+            recovery: None,
+            ids: HashSet::new(),
+            next: HashMap::new(),
+            matched: HashMap::new(),
+        }
+    }
+
+    fn term(&self) -> u64 {
+        match &self.mode {
+            Mode::Fixed(_) => 0,
+            Mode::Elected(election) => election.term(),
+        }
+    }
+
+    fn sequencer(&self) -> Option<&str> {
+        match &self.mode {
+            Mode::Fixed(sequencer) => Some(sequencer),
+            Mode::Elected(election) => election.leader(),
+        }
+    }
+
+    /// Whether this node is the sequencer and may number values.
+    fn is_serving(&self, channel: &channel::MessageChannel) -> bool {
+        self.sequencer() == Some(channel.node_id.as_str())
+            && self.recovered == Some(self.term())
+            && self.promised == self.term()
+    }
+
+    fn promise(&mut self, term: u64) {
+        if term > self.promised {
+            self.promised = term;
+            // slots held for older terms may have been overwritten elsewhere
+            self.accepted = self.delivered;
+        }
+    }
+
+    fn submit(
+        &mut self,
+        entry: Entry<T>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        if self.is_serving(channel) {
+            self.sequence(entry, channel)
+        } else if let Some(sequencer) = self.sequencer() {
+            let sequencer = sequencer.to_string();
+            channel.send(&sequencer, &OrderPayload::OrderSubmit { entry })
+        } else {
+            // resubmitted once a sequencer is elected
+            Ok(())
+        }
+    }
+
+    fn sequence(
+        &mut self,
+        entry: Entry<T>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        if !self.ids.insert(entry.id.clone()) {
+            return Ok(());
+        }
+        self.log.push(Slot {
+            term: self.promised,
+            entry: Some(entry),
+        });
+        self.accepted = self.log.len() as u64;
+        self.replicate(channel)
+    }
+
+    fn replicate(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        self.advance_commit(channel);
+        for peer in peers(channel) {
+            let from = self.next.get(&peer).copied().unwrap_or(self.delivered);
+            let from = from.min(self.log.len() as u64);
+            let slots: Vec<Slot<T>> = self.log[from as usize..]
+                .iter()
+                .take(MAX_APPEND)
+                .cloned()
+                .collect();
+            let append = OrderPayload::OrderAppend {
+                term: self.promised,
+                from,
+                slots,
+                commit: self.commit,
+            };
+            channel.send(&peer, &append)?;
+        }
+        Ok(())
+    }
+
+    fn advance_commit(&mut self, channel: &channel::MessageChannel) {
+        let mut lens: Vec<u64> = peers(channel)
+            .iter()
+            .map(|peer| self.matched.get(peer).copied().unwrap_or(0))
+            .collect();
+        lens.push(self.log.len() as u64);
+        lens.sort_by_key(|len| std::cmp::Reverse(*len));
+        let committed = lens[majority(channel) - 1];
+        self.commit = self.commit.max(committed);
+        self.deliver(&channel.node_id);
+    }
+
+    fn start_recovery(
+        &mut self,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let term = self.term();
+        self.promise(term);
+        let mut logs = HashMap::new();
+        logs.insert(
+            channel.node_id.clone(),
+            self.log[self.delivered as usize..].to_vec(),
+        );
+        self.recovery = Some(Recovery {
+            term,
+            from: self.delivered,
+            logs,
+        });
+        let recover = OrderPayload::<T>::OrderRecover {
+            term,
+            from: self.delivered,
+        };
+        for peer in peers(channel) {
+            channel.send(&peer, &recover)?;
+        }
+        Ok(())
+    }
+
+    /// Adopts, for every slot, the one written in the highest term among a majority.
+    fn finish_recovery(&mut self, recovery: Recovery<T>) {
+        let len = recovery.logs.values().map(Vec::len).max().unwrap_or(0);
+        let merged: Vec<Slot<T>> = (0..len)
+            .map(|i| {
+                let entry = recovery
+                    .logs
+                    .values()
+                    .filter_map(|log| log.get(i))
+                    .max_by_key(|slot| slot.term)
+                    .and_then(|slot| slot.entry.clone());
+                Slot {
+                    term: recovery.term,
+                    entry,
+                }
+            })
+            .collect();
+
+        self.log.truncate(recovery.from as usize);
+        self.log.extend(merged);
+        self.accepted = self.log.len() as u64;
+        self.ids = self
+            .log
+            .iter()
+            .filter_map(|slot| slot.entry.as_ref().map(|e| e.id.clone()))
+            .collect();
+        self.next.clear();
+        self.matched.clear();
+        self.recovered = Some(recovery.term);
+    }
+
+    fn deliver(&mut self, node_id: &str) {
+        while self.delivered < self.commit.min(self.accepted) {
+            let slot = &self.log[self.delivered as usize];
+            if let Some(entry) = &slot.entry {
+                if entry.id.node == node_id {
+                    self.pending.remove(&entry.id.seq);
+                }
+                self.values.push(entry.value.clone());
+            }
+            self.delivered += 1;
+        }
+    }
+}
+
+impl<T> TotalOrder<T> for Sequencer<T>
+where
+    T: Clone + Serialize,
+{
+    fn broadcast(
+        &mut self,
+        value: T,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.pending.insert(seq, value.clone());
+        let id = EntryId {
+            node: channel.node_id.clone(),
+            seq,
+        };
+        self.submit(Entry { id, value }, channel)
+    }
+
+    fn handle(
+        &mut self,
+        src: &str,
+        _in_reply_to: Option<usize>,
+        payload: &Payload<T>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let payload = match payload {
+            Payload::Order(payload) => payload,
+            Payload::Election(payload) => {
+                return match &mut self.mode {
+                    Mode::Elected(election) => election.handle(src, payload, channel),
+                    Mode::Fixed(_) => Ok(()),
+                };
+            }
+            Payload::Kv(_) => return Ok(()),
+        };
+        match payload {
+            OrderPayload::OrderSubmit { entry } => {
+                // dropped while recovering, the submitter will try again
+                if self.is_serving(channel) {
+                    self.sequence(entry.clone(), channel)?;
+                }
+            }
+            OrderPayload::OrderAppend {
+                term,
+                from,
+                slots,
+                commit,
+            } => {
+                if *term < self.promised {
+                    return Ok(());
+                }
+                self.promise(*term);
+                // appending past the slots held for this term would leave a gap
+                if *from <= self.accepted {
+                    for (i, slot) in slots.iter().enumerate() {
+                        let index = *from as usize + i;
+                        if index < self.log.len() {
+                            self.log[index] = slot.clone();
+                        } else {
+                            self.log.push(slot.clone());
+                        }
+                    }
+                    self.accepted = self.accepted.max(*from + slots.len() as u64);
+                    self.commit = self.commit.max(*commit);
+                    self.deliver(&channel.node_id);
+                }
+                let ack = OrderPayload::<T>::OrderAck {
+                    term: *term,
+                    len: self.accepted,
+                };
+                channel.send(src, &ack)?;
+            }
+            OrderPayload::OrderAck { term, len } => {
+                if *term == self.promised && self.is_serving(channel) {
+                    self.next.insert(src.to_string(), *len);
+                    self.matched.insert(src.to_string(), *len);
+                    self.advance_commit(channel);
+                }
+            }
+            OrderPayload::OrderRecover { term, from } => {
+                if *term < self.promised {
+                    return Ok(());
+                }
+                self.promise(*term);
+                let from = (*from as usize).min(self.log.len());
+                let log = OrderPayload::OrderLog {
+                    term: *term,
+                    slots: self.log[from..].to_vec(),
+                };
+                channel.send(src, &log)?;
+            }
+            OrderPayload::OrderLog { term, slots } => {
+                let Some(recovery) = &mut self.recovery else {
+                    return Ok(());
+                };
+                if recovery.term != *term {
+                    return Ok(());
+                }
+                recovery.logs.insert(src.to_string(), slots.clone());
+                if recovery.logs.len() >= majority(channel) {
+                    let recovery = self.recovery.take().unwrap();
+                    self.finish_recovery(recovery);
+                    self.replicate(channel)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if let Mode::Elected(election) = &mut self.mode {
+            election.tick(channel)?;
+            let term = election.term();
+            let recovering = self.recovery.as_ref().is_some_and(|r| r.term == term);
+            if election.is_leader() && self.recovered != Some(term) && !recovering {
+                self.start_recovery(channel)?;
+            }
+        }
+
+        if self.is_serving(channel) {
+            // doubles as a heartbeat carrying the commit index
+            self.replicate(channel)?;
+        }
+
+        if !self.pending.is_empty() && self.last_submitted.elapsed() >= self.resubmit_interval {
+            self.last_submitted = time::Instant::now();
+            let entries: Vec<Entry<T>> = self
+                .pending
+                .iter()
+                .map(|(seq, value)| Entry {
+                    id: EntryId {
+                        node: channel.node_id.clone(),
+                        seq: *seq,
+                    },
+                    value: value.clone(),
+                })
+                .collect();
+            for entry in entries {
+                self.submit(entry, channel)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn take_delivered(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.values)
+    }
+}
+
+enum Request {
+    Propose,
+    Read,
+}
+
+struct InFlight {
+    msg_id: usize,
+    request: Request,
+    sent: time::Instant,
+}
+
+pub struct Consensus<T> {
+    service: String,
+    timeout: time::Duration,
+
+    pending: VecDeque<Entry<T>>,
+    next_seq: u64,
+
+    /// The next slot to deliver.
+    slot: u64,
+    in_flight: Option<InFlight>,
+    values: Vec<T>,
+}
+
+impl<T> Consensus<T>
+where
+    T: Clone + Serialize + DeserializeOwned,
+{
+    /// Creates a consensus backend keeping its log in `service`, and retrying requests the
+    /// service did not answer within `timeout`.
+    pub fn new(service: &str, timeout: time::Duration) -> Self {
+        Self {
+            service: service.to_string(),
+            timeout,
+            pending: VecDeque::new(),
+            next_seq: 0,
+            slot: 0,
+            in_flight: None,
+            values: Vec::new(),
+        }
+    }
+
+    fn key(&self) -> serde_json::Value {
+        serde_json::Value::from(format!("total-order/{}", self.slot))
+    }
+
+    /// Proposes the oldest pending entry for the next slot, or reads it if there is none.
+    fn step(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if self.in_flight.is_some() {
+            return Ok(());
+        }
+        match self.pending.front() {
+            Some(entry) => {
+                let to = serde_json::to_value(entry).map_err(|_| "unserializable entry")?;
+                let cas = kv::Payload::Cas {
+                    key: self.key(),
+                    from: serde_json::Value::Null,
+                    to,
+                    create_if_not_exists: true,
+                };
+                self.request(Request::Propose, &cas, channel)
+            }
+            None => self.read(channel),
+        }
+    }
+
+    fn read(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        let read = kv::Payload::Read { key: self.key() };
+        self.request(Request::Read, &read, channel)
+    }
+
+    fn request(
+        &mut self,
+        request: Request,
+        payload: &kv::Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let msg_id = channel.next_msg_id();
+        self.in_flight = Some(InFlight {
+            msg_id,
+            request,
+            sent: time::Instant::now(),
+        });
+        channel.send_with_id(&self.service, msg_id, payload)
+    }
+
+    fn deliver(&mut self, entry: Entry<T>) {
+        self.pending.retain(|pending| pending.id != entry.id);
+        self.values.push(entry.value);
+        self.slot += 1;
+    }
+}
+
+impl<T> TotalOrder<T> for Consensus<T>
+where
+    T: Clone + Serialize + DeserializeOwned,
+{
+    fn broadcast(
+        &mut self,
+        value: T,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let id = EntryId {
+            node: channel.node_id.clone(),
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        self.pending.push_back(Entry { id, value });
+        self.step(channel)
+    }
+
+    fn handle(
+        &mut self,
+        _src: &str,
+        in_reply_to: Option<usize>,
+        payload: &Payload<T>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        let Payload::Kv(payload) = payload else {
+            return Ok(());
+        };
+        let Some(in_flight) = &self.in_flight else {
+            return Ok(());
+        };
+        if Some(in_flight.msg_id) != in_reply_to {
+            return Ok(());
+        }
+        let in_flight = self.in_flight.take().unwrap();
+
+        match (in_flight.request, payload) {
+            (Request::Propose, kv::Payload::CasOk) => {
+                let entry = self.pending.pop_front().ok_or("nothing was proposed")?;
+                self.deliver(entry);
+                self.step(channel)
+            }
+            // the slot is taken, find out by what
+            (Request::Propose, kv::Payload::Error { code, .. })
+                if *code == kv::PRECONDITION_FAILED =>
+            {
+                self.read(channel)
+            }
+            (Request::Read, kv::Payload::ReadOk { value }) => {
+                let entry = serde_json::from_value(value.clone()).map_err(|_| "invalid entry")?;
+                self.deliver(entry);
+                self.step(channel)
+            }
+            // caught up, propose right away or poll again on the next tick
+            (Request::Read, kv::Payload::Error { code, .. }) if *code == kv::KEY_DOES_NOT_EXIST => {
+                if self.pending.is_empty() {
+                    Ok(())
+                } else {
+                    self.step(channel)
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        if self
+            .in_flight
+            .as_ref()
+            .is_some_and(|in_flight| in_flight.sent.elapsed() >= self.timeout)
+        {
+            self.in_flight = None;
+        }
+        self.step(channel)
+    }
+
+    fn take_delivered(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.values)
+    }
+}
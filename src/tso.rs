@@ -0,0 +1,175 @@
+//! Timestamp oracle.
+//!
+//! Maelstrom's `lin-tso` service answers every `ts` request with a timestamp greater than any
+//! it handed out before, which gives transactions a global order for MVCC and snapshot reads.
+//! A [`Client`] asks it for timestamps. Without the service, a [`StandIn`] does the same
+//! thing, either in-process through [`Client::local`] or hosted by one node for the others.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+use crate::message;
+
+pub const LIN_TSO: &str = "lin-tso";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    Ts,
+    TsOk { ts: u64 },
+}
+
+/// Hands out increasing timestamps. They start from the wall clock in microseconds, so they
+/// keep increasing across restarts of the node that hosts it unless its clock is set back.
+#[derive(Debug, Default)]
+pub struct StandIn {
+    last: u64,
+}
+
+impl StandIn {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn timestamp(&mut self) -> u64 {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        self.last = (self.last + 1).max(now);
+        self.last
+    }
+
+    /// Answers a `ts` request from another node.
+    pub fn handle<T>(
+        &mut self,
+        received: &message::Message<T>,
+        payload: &Payload,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        if let Payload::Ts = payload {
+            channel.reply(
+                received,
+                &Payload::TsOk {
+                    ts: self.timestamp(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Request {
+    token: usize,
+    sent: time::Instant,
+}
+
+enum Backend {
+    Service(String),
+    Local(StandIn),
+}
+
+/// Requests timestamps and collects the answers by the token each request returned.
+pub struct Client {
+    backend: Backend,
+    timeout: time::Duration,
+
+    next_token: usize,
+    in_flight: HashMap<usize, Request>,
+    ready: VecDeque<(usize, u64)>,
+}
+
+impl Client {
+    /// Creates a client of `service`, which is `LIN_TSO` or a node hosting a `StandIn`,
+    /// asking again when it did not answer within `timeout`.
+    pub fn new(service: &str, timeout: time::Duration) -> Self {
+        Self::with_backend(Backend::Service(service.to_string()), timeout)
+    }
+
+    /// Creates a client answered right away by a stand-in of its own.
+    pub fn local() -> Self {
+        Self::with_backend(Backend::Local(StandIn::new()), time::Duration::MAX)
+    }
+
+    fn with_backend(backend: Backend, timeout: time::Duration) -> Self {
+        Self {
+            backend,
+            timeout,
+            next_token: 0,
+            in_flight: HashMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Asks for a timestamp, returning the token its answer will come with.
+    pub fn request(
+        &mut self,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<usize, &'static str> {
+        let token = self.next_token;
+        self.next_token += 1;
+        self.send(token, channel)?;
+        Ok(token)
+    }
+
+    fn send(
+        &mut self,
+        token: usize,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<(), &'static str> {
+        match &mut self.backend {
+            Backend::Service(service) => {
+                let msg_id = channel.next_msg_id();
+                self.in_flight.insert(
+                    msg_id,
+                    Request {
+                        token,
+                        sent: time::Instant::now(),
+                    },
+                );
+                channel.send_with_id(service, msg_id, &Payload::Ts)
+            }
+            Backend::Local(stand_in) => {
+                self.ready.push_back((token, stand_in.timestamp()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Handles an answer from the service.
+    pub fn handle(&mut self, in_reply_to: Option<usize>, payload: &Payload) {
+        let Payload::TsOk { ts } = payload else {
+            return;
+        };
+        if let Some(request) = in_reply_to.and_then(|id| self.in_flight.remove(&id)) {
+            self.ready.push_back((request.token, *ts));
+        }
+    }
+
+    /// Takes the answers received so far as pairs of token and timestamp.
+    pub fn take_ready(&mut self) -> Vec<(usize, u64)> {
+        self.ready.drain(..).collect()
+    }
+
+    /// Asks again for timestamps that were not answered in time.
+    pub fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        let expired: Vec<usize> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.sent.elapsed() >= self.timeout)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        for msg_id in expired {
+            // a late answer to the old request is ignored, its timestamp is just skipped
+            let request = self.in_flight.remove(&msg_id).unwrap();
+            self.send(request.token, channel)?;
+        }
+        Ok(())
+    }
+}
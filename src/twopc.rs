@@ -0,0 +1,747 @@
+//! Two-phase commit for transactions spanning several nodes.
+//!
+//! A [`Coordinator`] asks every participant to prepare its part of a transaction and commits
+//! only if all of them vote yes. Both roles write to a [`DecisionLog`] before acting, so they
+//! can be rebuilt with `recover` after a crash.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::time;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::channel;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TxId {
+    pub coordinator: String,
+    pub seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload<T> {
+    TxPrepare {
+        tx: TxId,
+        op: T,
+    },
+    TxVote {
+        tx: TxId,
+        commit: bool,
+    },
+    TxCommit {
+        tx: TxId,
+    },
+    TxAbort {
+        tx: TxId,
+    },
+    TxAck {
+        tx: TxId,
+    },
+    /// Sent by an in-doubt participant to learn the outcome of a transaction.
+    TxStatus {
+        tx: TxId,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "record")]
+pub enum Record<T> {
+    /// Coordinator: the transaction was started with these participants.
+    Begun {
+        tx: TxId,
+        participants: Vec<String>,
+    },
+    /// Participant: voted to commit, and must not forget `op` until the outcome is known.
+    Prepared {
+        tx: TxId,
+        coordinator: String,
+        op: T,
+    },
+    Committed {
+        tx: TxId,
+    },
+    Aborted {
+        tx: TxId,
+    },
+    /// Coordinator: every participant acknowledged the outcome.
+    Done {
+        tx: TxId,
+    },
+}
+
+/// Durable storage for two-phase commit records.
+///
+/// `append` must only return once the record would survive a crash.
+pub trait DecisionLog<T> {
+    fn append(&mut self, record: Record<T>) -> Result<(), &'static str>;
+
+    fn records(&self) -> Vec<Record<T>>;
+}
+
+pub struct MemoryLog<T> {
+    records: Vec<Record<T>>,
+}
+
+impl<T> MemoryLog<T> {
+    pub fn new() -> Self {
+        Self {
+            records: Vec::new(),
+        }
+    }
+}
+
+impl<T> Default for MemoryLog<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> DecisionLog<T> for MemoryLog<T> {
+    fn append(&mut self, record: Record<T>) -> Result<(), &'static str> {
+        self.records.push(record);
+        Ok(())
+    }
+
+    fn records(&self) -> Vec<Record<T>> {
+        self.records.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub tx: TxId,
+    pub committed: bool,
+}
+
+struct Transaction {
+    participants: Vec<String>,
+    votes: HashSet<String>,
+    decision: Option<bool>,
+    acked: HashSet<String>,
+    last_sent: time::Instant,
+}
+
+pub struct Coordinator<L> {
+    log: L,
+    timeout: time::Duration,
+    next_seq: u64,
+    transactions: BTreeMap<TxId, Transaction>,
+}
+
+impl<L> Coordinator<L> {
+    pub fn new(log: L, timeout: time::Duration) -> Self {
+        Self {
+            log,
+            timeout,
+            next_seq: 0,
+            transactions: BTreeMap::new(),
+        }
+    }
+
+    /// Simulates a crash, keeping only what was written to the log.
+    pub fn into_log(self) -> L {
+        self.log
+    }
+
+    pub fn is_pending(&self, tx: &TxId) -> bool {
+        self.transactions.contains_key(tx)
+    }
+
+    /// Rebuilds a coordinator from its log. Transactions that were not decided before the
+    /// crash are aborted, and decisions that were not acknowledged are sent again on tick.
+    pub fn recover<T>(mut log: L, timeout: time::Duration) -> Result<Self, &'static str>
+    where
+        L: DecisionLog<T>,
+    {
+        let mut next_seq = 0;
+        let mut transactions = BTreeMap::new();
+        for record in log.records() {
+            match record {
+                Record::Begun { tx, participants } => {
+                    next_seq = next_seq.max(tx.seq + 1);
+                    let transaction = Transaction {
+                        participants,
+                        votes: HashSet::new(),
+                        decision: None,
+                        acked: HashSet::new(),
+                        last_sent: time::Instant::now(),
+                    };
+                    transactions.insert(tx, transaction);
+                }
+                Record::Committed { tx } => {
+                    if let Some(transaction) = transactions.get_mut(&tx) {
+                        transaction.decision = Some(true);
+                    }
+                }
+                Record::Aborted { tx } => {
+                    if let Some(transaction) = transactions.get_mut(&tx) {
+                        transaction.decision = Some(false);
+                    }
+                }
+                Record::Done { tx } => {
+                    transactions.remove(&tx);
+                }
+                Record::Prepared { .. } => {}
+            }
+        }
+
+        for (tx, transaction) in transactions.iter_mut() {
+            if transaction.decision.is_none() {
+                log.append(Record::Aborted { tx: tx.clone() })?;
+                transaction.decision = Some(false);
+            }
+            // force the decision to be resent on the next tick
+            transaction.last_sent = overdue(timeout);
+        }
+
+        Ok(Self {
+            log,
+            timeout,
+            next_seq,
+            transactions,
+        })
+    }
+
+    /// Starts a transaction, sending each participant its part of the operation. A node can
+    /// only take part once in a transaction.
+    pub fn begin<T>(
+        &mut self,
+        ops: Vec<(String, T)>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<TxId, &'static str>
+    where
+        L: DecisionLog<T>,
+        T: Serialize,
+    {
+        let participants: Vec<String> = ops.iter().map(|(node, _)| node.clone()).collect();
+        let unique: BTreeSet<&String> = participants.iter().collect();
+        if unique.len() != participants.len() {
+            return Err("duplicate participant");
+        }
+
+        let tx = TxId {
+            coordinator: channel.node_id.clone(),
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        self.log.append(Record::Begun {
+            tx: tx.clone(),
+            participants: participants.clone(),
+        })?;
+
+        for (node, op) in ops {
+            channel.send(&node, &Payload::TxPrepare { tx: tx.clone(), op })?;
+        }
+
+        let transaction = Transaction {
+            participants,
+            votes: HashSet::new(),
+            decision: None,
+            acked: HashSet::new(),
+            last_sent: time::Instant::now(),
+        };
+        self.transactions.insert(tx.clone(), transaction);
+        Ok(tx)
+    }
+
+    /// Handles a message from a participant, returning the outcome once it is decided.
+    pub fn handle<T>(
+        &mut self,
+        src: &str,
+        payload: &Payload<T>,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<Option<Outcome>, &'static str>
+    where
+        L: DecisionLog<T>,
+        T: Serialize,
+    {
+        match payload {
+            Payload::TxVote { tx, commit } => {
+                let Some(transaction) = self.transactions.get_mut(tx) else {
+                    return Ok(None);
+                };
+                if transaction.decision.is_some()
+                    || !transaction.participants.iter().any(|p| p == src)
+                {
+                    return Ok(None);
+                }
+                if !commit {
+                    return self.decide::<T>(tx, false, channel).map(Some);
+                }
+                transaction.votes.insert(src.to_string());
+                if transaction.votes.len() == transaction.participants.len() {
+                    return self.decide::<T>(tx, true, channel).map(Some);
+                }
+                Ok(None)
+            }
+            Payload::TxAck { tx } => {
+                let Some(transaction) = self.transactions.get_mut(tx) else {
+                    return Ok(None);
+                };
+                if !transaction.participants.iter().any(|p| p == src) {
+                    return Ok(None);
+                }
+                transaction.acked.insert(src.to_string());
+                if transaction.acked.len() == transaction.participants.len() {
+                    self.log.append(Record::Done { tx: tx.clone() })?;
+                    self.transactions.remove(tx);
+                }
+                Ok(None)
+            }
+            Payload::TxStatus { tx } => {
+                let decision = match self.transactions.get(tx) {
+                    // still collecting votes, the participant will ask again
+                    Some(Transaction { decision: None, .. }) => return Ok(None),
+                    Some(Transaction {
+                        decision: Some(committed),
+                        ..
+                    }) => *committed,
+                    // forgotten transactions were either acknowledged or never decided
+                    None => self.log.records().iter().any(
+                        |record| matches!(record, Record::Committed { tx: committed } if committed == tx),
+                    ),
+                };
+                channel.send(src, &decision_payload::<T>(tx, decision))?;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Aborts transactions whose votes did not arrive in time, and resends decisions that
+    /// were not acknowledged.
+    pub fn tick<T>(
+        &mut self,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<Vec<Outcome>, &'static str>
+    where
+        L: DecisionLog<T>,
+        T: Serialize,
+    {
+        let mut outcomes = Vec::new();
+        let expired: Vec<TxId> = self
+            .transactions
+            .iter()
+            .filter(|(_, t)| t.last_sent.elapsed() >= self.timeout)
+            .map(|(tx, _)| tx.clone())
+            .collect();
+
+        for tx in expired {
+            let transaction = self.transactions.get_mut(&tx).unwrap();
+            match transaction.decision {
+                None => outcomes.push(self.decide::<T>(&tx, false, channel)?),
+                Some(committed) => {
+                    transaction.last_sent = time::Instant::now();
+                    for node in &transaction.participants {
+                        if !transaction.acked.contains(node) {
+                            channel.send(node, &decision_payload::<T>(&tx, committed))?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(outcomes)
+    }
+
+    fn decide<T>(
+        &mut self,
+        tx: &TxId,
+        committed: bool,
+        channel: &mut channel::MessageChannel,
+    ) -> Result<Outcome, &'static str>
+    where
+        L: DecisionLog<T>,
+        T: Serialize,
+    {
+        // the decision must be durable before anyone hears about it
+        let record = if committed {
+            Record::Committed { tx: tx.clone() }
+        } else {
+            Record::Aborted { tx: tx.clone() }
+        };
+        self.log.append(record)?;
+
+        let transaction = self.transactions.get_mut(tx).unwrap();
+        transaction.decision = Some(committed);
+        transaction.last_sent = time::Instant::now();
+        for node in &transaction.participants {
+            channel.send(node, &decision_payload::<T>(tx, committed))?;
+        }
+        Ok(Outcome {
+            tx: tx.clone(),
+            committed,
+        })
+    }
+}
+
+/// An instant far enough in the past for `timeout` to have already expired.
+fn overdue(timeout: time::Duration) -> time::Instant {
+    let now = time::Instant::now();
+    now.checked_sub(timeout).unwrap_or(now)
+}
+
+fn decision_payload<T>(tx: &TxId, committed: bool) -> Payload<T> {
+    if committed {
+        Payload::TxCommit { tx: tx.clone() }
+    } else {
+        Payload::TxAbort { tx: tx.clone() }
+    }
+}
+
+/// The outcome of a transaction as seen by a participant, to be applied by the handler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision<T> {
+    Commit { tx: TxId, op: T },
+    Abort { tx: TxId },
+}
+
+struct Prepared<T> {
+    coordinator: String,
+    op: T,
+    since: time::Instant,
+}
+
+pub struct Participant<T, L> {
+    log: L,
+    timeout: time::Duration,
+    prepared: BTreeMap<TxId, Prepared<T>>,
+    /// The outcome of every transaction decided here, to answer prepares that come late.
+    decided: BTreeMap<TxId, bool>,
+}
+
+impl<T, L> Participant<T, L>
+where
+    T: Clone + Serialize,
+    L: DecisionLog<T>,
+{
+    pub fn new(log: L, timeout: time::Duration) -> Self {
+        Self {
+            log,
+            timeout,
+            prepared: BTreeMap::new(),
+            decided: BTreeMap::new(),
+        }
+    }
+
+    /// Simulates a crash, keeping only what was written to the log.
+    pub fn into_log(self) -> L {
+        self.log
+    }
+
+    /// Rebuilds a participant from its log. Transactions that were prepared but not decided
+    /// are in doubt: the participant cannot abort them on its own, so it asks the coordinator
+    /// for the outcome on the next tick.
+    pub fn recover(log: L, timeout: time::Duration) -> Self {
+        let mut prepared = BTreeMap::new();
+        let mut decided = BTreeMap::new();
+        for record in log.records() {
+            match record {
+                Record::Prepared {
+                    tx,
+                    coordinator,
+                    op,
+                } => {
+                    let since = overdue(timeout);
+                    prepared.insert(
+                        tx,
+                        Prepared {
+                            coordinator,
+                            op,
+                            since,
+                        },
+                    );
+                }
+                Record::Committed { tx } => {
+                    prepared.remove(&tx);
+                    decided.insert(tx, true);
+                }
+                Record::Aborted { tx } => {
+                    prepared.remove(&tx);
+                    decided.insert(tx, false);
+                }
+                _ => {}
+            }
+        }
+        Self {
+            log,
+            timeout,
+            prepared,
+            decided,
+        }
+    }
+
+    pub fn in_doubt(&self) -> impl Iterator<Item = &TxId> {
+        self.prepared.keys()
+    }
+
+    /// Handles a message from a coordinator. `vote` is asked whether the local part of a
+    /// transaction can be committed; once a decision arrives it is returned to be applied.
+    pub fn handle<F>(
+        &mut self,
+        src: &str,
+        payload: &Payload<T>,
+        channel: &mut channel::MessageChannel,
+        mut vote: F,
+    ) -> Result<Option<Decision<T>>, &'static str>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        match payload {
+            Payload::TxPrepare { tx, op } => {
+                // a retransmission, voting again could prepare a transaction twice
+                if let Some(&committed) = self.decided.get(tx) {
+                    let vote = Payload::<T>::TxVote {
+                        tx: tx.clone(),
+                        commit: committed,
+                    };
+                    channel.send(src, &vote)?;
+                    return Ok(None);
+                }
+                let commit = self.prepared.contains_key(tx) || vote(op);
+                if !commit {
+                    // the coordinator aborts on our vote, or on its timeout if the vote is lost
+                    self.decided.insert(tx.clone(), false);
+                }
+                if commit && !self.prepared.contains_key(tx) {
+                    self.log.append(Record::Prepared {
+                        tx: tx.clone(),
+                        coordinator: src.to_string(),
+                        op: op.clone(),
+                    })?;
+                    let prepared = Prepared {
+                        coordinator: src.to_string(),
+                        op: op.clone(),
+                        since: time::Instant::now(),
+                    };
+                    self.prepared.insert(tx.clone(), prepared);
+                }
+                channel.send(
+                    src,
+                    &Payload::<T>::TxVote {
+                        tx: tx.clone(),
+                        commit,
+                    },
+                )?;
+                Ok(None)
+            }
+            Payload::TxCommit { tx } | Payload::TxAbort { tx } => {
+                let committed = matches!(payload, Payload::TxCommit { .. });
+                let decision = match self.prepared.remove(tx) {
+                    Some(prepared) => {
+                        let record = if committed {
+                            Record::Committed { tx: tx.clone() }
+                        } else {
+                            Record::Aborted { tx: tx.clone() }
+                        };
+                        self.log.append(record)?;
+                        self.decided.insert(tx.clone(), committed);
+                        Some(if committed {
+                            Decision::Commit {
+                                tx: tx.clone(),
+                                op: prepared.op,
+                            }
+                        } else {
+                            Decision::Abort { tx: tx.clone() }
+                        })
+                    }
+                    // already applied, or we voted no
+                    None => None,
+                };
+                channel.send(src, &Payload::<T>::TxAck { tx: tx.clone() })?;
+                Ok(decision)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Asks coordinators about transactions that have been in doubt for too long.
+    pub fn tick(&mut self, channel: &mut channel::MessageChannel) -> Result<(), &'static str> {
+        for (tx, prepared) in self.prepared.iter_mut() {
+            if prepared.since.elapsed() >= self.timeout {
+                prepared.since = time::Instant::now();
+                channel.send(
+                    &prepared.coordinator,
+                    &Payload::<T>::TxStatus { tx: tx.clone() },
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init::Init;
+    use crate::message;
+
+    type Op = i64;
+
+    fn channel(node_id: &str) -> channel::MessageChannel {
+        channel::MessageChannel::from(&Init {
+            node_id: node_id.to_string(),
+            node_ids: ["n1", "n2", "n3"].map(str::to_string).to_vec(),
+        })
+    }
+
+    /// What was sent since the last call, by destination.
+    fn sent() -> Vec<(String, Payload<Op>)> {
+        channel::take_written()
+            .iter()
+            .map(|line| {
+                let message: message::Message<Payload<Op>> = serde_json::from_str(line).unwrap();
+                (message.dest, message.body.payload)
+            })
+            .collect()
+    }
+
+    fn timeout() -> time::Duration {
+        time::Duration::from_secs(1)
+    }
+
+    #[test]
+    fn coordinator_only_counts_participants() {
+        let mut c1 = channel("n1");
+        let mut coordinator = Coordinator::new(MemoryLog::<Op>::new(), timeout());
+        let ops = vec![("n2".to_string(), 1), ("n2".to_string(), 2)];
+        assert!(coordinator.begin(ops, &mut c1).is_err());
+
+        let ops = vec![("n2".to_string(), 1), ("n3".to_string(), 2)];
+        let tx = coordinator.begin(ops, &mut c1).unwrap();
+        let yes = Payload::TxVote {
+            tx: tx.clone(),
+            commit: true,
+        };
+        let no = Payload::TxVote {
+            tx: tx.clone(),
+            commit: false,
+        };
+        assert_eq!(coordinator.handle("n2", &yes, &mut c1), Ok(None));
+        assert_eq!(coordinator.handle("n4", &no, &mut c1), Ok(None));
+        assert_eq!(coordinator.handle("n2", &yes, &mut c1), Ok(None));
+        let outcome = coordinator.handle("n3", &yes, &mut c1).unwrap();
+        assert_eq!(
+            outcome,
+            Some(Outcome {
+                tx,
+                committed: true
+            })
+        );
+    }
+
+    #[test]
+    fn coordinator_recovers_from_its_log() {
+        let mut c1 = channel("n1");
+        let mut coordinator = Coordinator::new(MemoryLog::<Op>::new(), timeout());
+        let ops = vec![("n2".to_string(), 1), ("n3".to_string(), 2)];
+        let decided = coordinator.begin(ops.clone(), &mut c1).unwrap();
+        for node in ["n2", "n3"] {
+            let vote = Payload::TxVote {
+                tx: decided.clone(),
+                commit: true,
+            };
+            coordinator.handle(node, &vote, &mut c1).unwrap();
+        }
+        let ack = Payload::TxAck {
+            tx: decided.clone(),
+        };
+        coordinator.handle("n2", &ack, &mut c1).unwrap();
+        let undecided = coordinator.begin(ops, &mut c1).unwrap();
+        sent();
+
+        let log = coordinator.into_log();
+        let mut coordinator = Coordinator::recover(log, timeout()).unwrap();
+        assert!(coordinator.is_pending(&decided) && coordinator.is_pending(&undecided));
+        assert_eq!(coordinator.tick::<Op>(&mut c1), Ok(Vec::new()));
+        // acks are not logged, so n2 hears about the commit again
+        let mut resent = sent();
+        resent.sort_by_key(|(dest, _)| dest.clone());
+        let commit = Payload::TxCommit {
+            tx: decided.clone(),
+        };
+        let abort = Payload::TxAbort {
+            tx: undecided.clone(),
+        };
+        assert_eq!(
+            resent,
+            vec![
+                ("n2".to_string(), commit.clone()),
+                ("n2".to_string(), abort.clone()),
+                ("n3".to_string(), commit),
+                ("n3".to_string(), abort.clone()),
+            ]
+        );
+
+        // a participant in doubt learns the outcome from the log once it is forgotten
+        for node in ["n2", "n3"] {
+            let ack = Payload::TxAck {
+                tx: undecided.clone(),
+            };
+            coordinator.handle(node, &ack, &mut c1).unwrap();
+        }
+        assert!(!coordinator.is_pending(&undecided));
+        let status = Payload::TxStatus { tx: undecided };
+        coordinator.handle("n2", &status, &mut c1).unwrap();
+        assert_eq!(sent(), vec![("n2".to_string(), abort)]);
+    }
+
+    #[test]
+    fn participant_recovers_from_its_log() {
+        let mut c2 = channel("n2");
+        let mut participant = Participant::new(MemoryLog::new(), timeout());
+        let tx = TxId {
+            coordinator: "n1".to_string(),
+            seq: 0,
+        };
+        let prepare = Payload::TxPrepare {
+            tx: tx.clone(),
+            op: 7,
+        };
+        participant
+            .handle("n1", &prepare, &mut c2, |_| true)
+            .unwrap();
+        sent();
+
+        let log = participant.into_log();
+        let mut participant = Participant::recover(log, timeout());
+        assert_eq!(participant.in_doubt().collect::<Vec<_>>(), vec![&tx]);
+        participant.tick(&mut c2).unwrap();
+        assert_eq!(
+            sent(),
+            vec![("n1".to_string(), Payload::TxStatus { tx: tx.clone() })]
+        );
+
+        let commit = Payload::TxCommit { tx: tx.clone() };
+        let decision = participant
+            .handle("n1", &commit, &mut c2, |_| true)
+            .unwrap();
+        assert_eq!(
+            decision,
+            Some(Decision::Commit {
+                tx: tx.clone(),
+                op: 7
+            })
+        );
+        assert_eq!(
+            participant.handle("n1", &commit, &mut c2, |_| true),
+            Ok(None)
+        );
+
+        // a late prepare is answered from the log, without preparing again
+        let log = participant.into_log();
+        let mut participant = Participant::recover(log, timeout());
+        sent();
+        let decision = participant.handle("n1", &prepare, &mut c2, |_| panic!("voted again"));
+        assert_eq!(decision, Ok(None));
+        assert_eq!(participant.in_doubt().count(), 0);
+        assert_eq!(
+            sent(),
+            vec![("n1".to_string(), Payload::TxVote { tx, commit: true })]
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Broadcast values tagged with the node that accepted them and a per-node sequence number.
+//!
+//! Each node appends the values it accepts from clients to its own log. Since logs only grow,
+//! what a peer has seen is summarized by a version vector holding the length of every log it
+//! has, and bringing it up to date means sending the tail of each log past its version.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::intset;
+use crate::intset::IntSet;
+
+/// The number of values seen from each origin.
+pub type VersionVector = BTreeMap<String, u64>;
+
+/// Consecutive values from the log of `origin`, the first of which has sequence `from`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entries {
+    pub origin: String,
+    pub from: u64,
+    /// In log order, encoded like an `IntSet` as long as the values come in runs.
+    #[serde(with = "intset::runs")]
+    pub values: Vec<i64>,
+}
+
+#[derive(Default)]
+pub struct SequencedSet {
+    logs: BTreeMap<String, Vec<i64>>,
+    values: IntSet,
+}
+
+impl SequencedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn values(&self) -> &IntSet {
+        &self.values
+    }
+
+    /// Accepts a value at `origin`, usually this node, returning whether it was new.
+    pub fn accept(&mut self, origin: &str, value: i64) -> bool {
+        if !self.values.insert(value) {
+            return false;
+        }
+        self.logs.entry(origin.to_string()).or_default().push(value);
+        true
+    }
+
+    pub fn version_vector(&self) -> VersionVector {
+        self.logs
+            .iter()
+            .map(|(origin, log)| (origin.clone(), log.len() as u64))
+            .collect()
+    }
+
+    /// The entries a peer at version `theirs` is missing.
+    pub fn missing(&self, theirs: &VersionVector) -> Vec<Entries> {
+        self.logs
+            .iter()
+            .filter_map(|(origin, log)| {
+                let from = theirs.get(origin).copied().unwrap_or(0);
+                let values = log.get(from as usize..)?;
+                if values.is_empty() {
+                    return None;
+                }
+                Some(Entries {
+                    origin: origin.clone(),
+                    from,
+                    values: values.to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    /// Appends entries received from a peer, ignoring the ones already seen. Entries that
+    /// would leave a gap in a log are dropped, they will be sent again from our version.
+    /// Returns the number of values that were new.
+    pub fn apply(&mut self, entries: &[Entries]) -> usize {
+        let mut applied = 0;
+        for entries in entries {
+            let log = self.logs.entry(entries.origin.clone()).or_default();
+            let len = log.len() as u64;
+            if entries.from > len {
+                continue;
+            }
+            let skip = (len - entries.from) as usize;
+            for &value in entries.values.iter().skip(skip) {
+                log.push(value);
+                self.values.insert(value);
+                applied += 1;
+            }
+        }
+        applied
+    }
+}
+
+/// Advances `version` past the entries sent to a peer.
+pub fn advance(version: &mut VersionVector, entries: &[Entries]) {
+    for entries in entries {
+        let seen = version.entry(entries.origin.clone()).or_default();
+        *seen = (*seen).max(entries.from + entries.values.len() as u64);
+    }
+}